@@ -101,6 +101,10 @@ impl Shell {
         }
     }
 
+    pub fn get_config(&self) -> ShellConfig {
+        self.config
+    }
+
     pub fn verbose(&mut self, callback: ShellCallback) -> IoResult<()> {
         if self.config.verbose { return callback(self) }
         Ok(())
@@ -132,6 +136,21 @@ impl Shell {
         Ok(())
     }
 
+    /// Same as `say_status`, but the line is left open and is written over by
+    /// the next status updated this way, for progress which keeps changing.
+    pub fn update_status<T: Show, U: Show>(&mut self, status: T, message: U,
+                                           color: Color) -> IoResult<()> {
+        try!(self.reset());
+        try!(self.write_str("\r"));
+        if color != BLACK { try!(self.fg(color)); }
+        if self.supports_attr(Bold) { try!(self.attr(Bold)); }
+        try!(self.write_str(format!("{:>12}", status).as_slice()));
+        try!(self.reset());
+        try!(self.write_str(format!(" {}", message).as_slice()));
+        try!(self.flush());
+        Ok(())
+    }
+
     fn fg(&mut self, color: color::Color) -> IoResult<bool> {
         match self.terminal {
             Colored(ref mut c) => c.fg(color),
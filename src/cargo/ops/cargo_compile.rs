@@ -31,6 +31,7 @@ use core::{MultiShell, Source, SourceId, PackageSet, Package, Target, PackageId}
 use core::resolver::Method;
 use ops::{mod, BuildOutput};
 use sources::{PathSource};
+use sources::git;
 use util::config::{Config, ConfigValue};
 use util::{CargoResult, Wrap, config, internal, human, ChainError, profile};
 
@@ -122,6 +123,11 @@ pub fn compile_pkg(package: &Package, options: &mut CompileOptions)
 
     debug!("packages={}", packages);
 
+    let fetched = git::fetch_totals();
+    if fetched.repositories > 0 {
+        try!(config.shell().verbose(|s| s.status("Fetched", fetched)));
+    }
+
     let to_build = match spec {
         Some(spec) => {
             let pkgid = try!(resolve_with_overrides.query(spec));
@@ -1,4 +1,11 @@
 pub use self::utils::{GitRemote, GitDatabase, GitCheckout, GitRevision, fetch};
-pub use self::source::{GitSource, canonicalize_url};
+pub use self::utils::GitCheckoutHandle;
+pub use self::utils::{fetch_with_credentials, CredentialProvider};
+pub use self::utils::{CheckoutState, GitError, GitErrorKind, GitResult};
+pub use self::utils::{CheckoutUpdate, FetchStats, FetchTotals, fetch_totals};
+pub use self::utils::ChangeKind;
+pub use self::utils::{GitConfig, git_configuration, take_warnings, show_progress};
+pub use self::source::{GitSource, canonicalize_url, checkout_path};
+pub use self::source::record_default_branch;
 mod utils;
 mod source;
@@ -1,23 +1,41 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fmt::{mod, Show, Formatter};
 use std::hash::Hasher;
 use std::hash::sip::SipHasher;
 use std::mem;
+use std::io::fs::PathExtensions;
+use term::color::BLACK;
 use url::{mod, Url};
 
 use core::source::{Source, SourceId};
 use core::GitReference;
 use core::{Package, PackageId, Summary, Registry, Dependency};
-use util::{CargoResult, Config, to_hex};
+use util::{CargoResult, Config, FromError, internal, to_hex};
 use sources::PathSource;
-use sources::git::utils::{GitRemote, GitRevision};
+use sources::git::utils::{GitRemote, GitRevision, git_configuration, offline};
+use sources::git::utils::{revision_not_found, take_warnings, show_progress};
+
+// The git sources of dependencies which didn't name a branch, tag or revision
+// and got the branch of `git.default-branch`, as found while reading manifests.
+thread_local!(static DEFAULTED: RefCell<HashSet<SourceId>> = RefCell::new(HashSet::new()))
+
+/// Records that a dependency on `source_id` didn't say what to build, so it's
+/// built from the default branch.
+pub fn record_default_branch(source_id: &SourceId) {
+    DEFAULTED.with(|defaulted| defaulted.borrow_mut().insert(source_id.clone()));
+}
 
 /* TODO: Refactor GitSource to delegate to a PathSource
  */
 pub struct GitSource<'a, 'b:'a> {
     remote: GitRemote,
     reference: GitReference,
+    // The reference as written in the manifest, `reference` is replaced with
+    // the locked revision if there is one.
+    manifest_reference: GitReference,
     db_path: Path,
-    checkout_path: Path,
+    checkouts_path: Path,
     source_id: SourceId,
     path_source: Option<PathSource>,
     rev: Option<GitRevision>,
@@ -40,25 +58,18 @@ impl<'a, 'b> GitSource<'a, 'b> {
         let db_path = config.git_db_path()
             .join(ident.as_slice());
 
-        let reference_path = match *reference {
-            GitReference::Branch(ref s) |
-            GitReference::Tag(ref s) |
-            GitReference::Rev(ref s) => s.to_string(),
-        };
-        let checkout_path = config.git_checkout_path()
-                                  .join(ident)
-                                  .join(reference_path);
-
+        let manifest_reference = reference.clone();
         let reference = match source_id.get_precise() {
             Some(s) => GitReference::Rev(s.to_string()),
-            None => source_id.git_reference().unwrap().clone(),
+            None => reference.clone(),
         };
 
         GitSource {
             remote: remote,
             reference: reference,
+            manifest_reference: manifest_reference,
             db_path: db_path,
-            checkout_path: checkout_path,
+            checkouts_path: config.git_checkout_path(),
             source_id: source_id.clone(),
             path_source: None,
             rev: None,
@@ -69,6 +80,145 @@ impl<'a, 'b> GitSource<'a, 'b> {
     pub fn get_url(&self) -> &Url {
         self.remote.get_url()
     }
+
+    /// Writes the files of the revision this source is pinned to into `dst` as
+    /// a plain directory without a `.git`, for vendoring the dependency.
+    pub fn vendor(&self, dst: &Path) -> CargoResult<()> {
+        let ret = self.export(dst);
+        try!(self.show_warnings());
+        ret
+    }
+
+    fn export(&self, dst: &Path) -> CargoResult<()> {
+        let rev = match self.rev {
+            Some(ref rev) => rev,
+            None => return Err(internal("update() must be called before vendor()")),
+        };
+        let repo = try!(self.remote.db_at(&self.db_path));
+        let path = checkout_path(&self.checkouts_path, self.remote.get_url(), rev);
+        let (checkout, _) = try!(repo.copy_to(rev.clone(), &path));
+        checkout.export(dst)
+    }
+
+    /// Shows the warnings which came up while working with the repository.
+    fn show_warnings(&self) -> CargoResult<()> {
+        for warning in take_warnings().into_iter() {
+            try!(self.config.shell().warn(warning));
+        }
+        Ok(())
+    }
+
+    /// Builds a dependency which didn't name a branch from the branch the
+    /// remote's `HEAD` points at, as `git clone` would, rather than from
+    /// `master`. `git.default-branch` takes precedence over the remote.
+    fn use_remote_default_branch(&mut self) -> CargoResult<()> {
+        let config = try!(git_configuration());
+        if config.default_branch.is_some() || offline() { return Ok(()) }
+        match self.remote.default_branch() {
+            Ok(Some(branch)) => {
+                self.reference = GitReference::Branch(branch.clone());
+                self.manifest_reference = GitReference::Branch(branch);
+            }
+            Ok(None) => {}
+            // `master` may well be there anyway, so this isn't fatal
+            Err(e) => {
+                log!(5, "failed to find the default branch of `{}`: {}",
+                     self.remote, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn fetch_and_checkout(&mut self) -> CargoResult<()> {
+        let actual_rev = self.remote.rev_for(&self.db_path, &self.reference);
+        let should_update = actual_rev.is_err() ||
+                            self.source_id.get_precise().is_none();
+
+        let (repo, actual_rev) = if should_update {
+            // Cloning can take a good while, so say so rather than making it
+            // look like a quick update.
+            let status = if self.db_path.exists() { "Updating" } else { "Cloning" };
+            try!(self.config.shell().status(status,
+                format!("git repository `{}`", self.remote.get_url())));
+
+            // Say which branch was picked for dependencies which didn't name
+            // one if `git.default-branch` or the remote changed it from
+            // `master`.
+            let defaulted = DEFAULTED.with(|defaulted| {
+                defaulted.borrow().contains(&self.source_id)
+            });
+            if defaulted && self.source_id.get_precise().is_none() {
+                try!(self.use_remote_default_branch());
+            }
+            match self.manifest_reference {
+                GitReference::Branch(ref branch)
+                    if defaulted && branch.as_slice() != "master" => {
+                    try!(self.config.shell().verbose(|s| {
+                        s.status("Using", format!("default branch `{}` of `{}`",
+                                                  branch, self.remote.get_url()))
+                    }));
+                }
+                _ => {}
+            }
+
+            log!(5, "updating git source `{}`", self.remote);
+            let repo = try!(self.remote.checkout(&self.db_path, &self.reference));
+            let rev = match self.source_id.get_precise() {
+                // The locked revision may have disappeared from the remote
+                Some(precise) => try!(repo.rev_for(&self.reference).map_err(|_| {
+                    revision_not_found(precise, self.remote.get_url())
+                })),
+                None => match repo.rev_for(&self.reference) {
+                    Ok(rev) => rev,
+                    Err(e) => {
+                        // Show what the reference was looked for in, which
+                        // usually makes it obvious why it wasn't found.
+                        let refs = try!(repo.references());
+                        let url = self.remote.get_url();
+                        try!(self.config.shell().verbose(|s| {
+                            try!(s.err().say(format!("references in the database \
+                                                      of `{}`:", url), BLACK));
+                            for &(ref name, ref rev) in refs.iter() {
+                                try!(s.err().say(format!("    {} {}", rev.short(),
+                                                         name), BLACK));
+                            }
+                            Ok(())
+                        }));
+                        return Err(FromError::from_error(e))
+                    }
+                },
+            };
+            (repo, rev)
+        } else {
+            (try!(self.remote.db_at(&self.db_path)), actual_rev.unwrap())
+        };
+
+        if self.source_id.get_precise().is_some() {
+            let moved = repo.moved_branch(&self.manifest_reference, &actual_rev);
+            match (&self.manifest_reference, moved) {
+                (&GitReference::Branch(ref branch), Some(tip)) => {
+                    try!(self.config.shell().warn(format!(
+                        "branch `{}` of {} has moved from {} to {}; run \
+                         `cargo update` to pick it up", branch,
+                        self.remote.get_url(), repo.short_rev(&actual_rev),
+                        repo.short_rev(&tip))));
+                }
+                _ => {}
+            }
+        }
+
+        let checkout_path = checkout_path(&self.checkouts_path,
+                                          self.remote.get_url(), &actual_rev);
+        let (_, update) = try!(repo.copy_to(actual_rev.clone(), &checkout_path));
+        log!(5, "checkout of `{}` at {}: {}", self.remote, actual_rev, update);
+
+        let source_id = self.source_id.with_precise(Some(actual_rev.to_string()));
+        let path_source = PathSource::new(&checkout_path, &source_id);
+
+        self.path_source = Some(path_source);
+        self.rev = Some(actual_rev);
+        self.path_source.as_mut().unwrap().update()
+    }
 }
 
 fn ident(url: &Url) -> String {
@@ -90,14 +240,32 @@ fn ident(url: &Url) -> String {
     format!("{}-{}", ident, to_hex(hasher.hash(&url)))
 }
 
+/// Returns the directory which revision `rev` of the repository at `url` is
+/// checked out into, given the root directory of all checkouts.
+///
+/// The layout is `<root>/<host>/<name>-<hash>/<rev>`, where `<name>-<hash>` is
+/// the stable identifier of the (canonicalized) url also used for databases.
+pub fn checkout_path(root: &Path, url: &Url, rev: &GitRevision) -> Path {
+    checkouts_dir(root, url).join(rev.to_string())
+}
+
+/// Returns the directory containing all checkouts of the repository at `url`.
+pub fn checkouts_dir(root: &Path, url: &Url) -> Path {
+    let host = match url.host() {
+        Some(host) => host.serialize(),
+        None => "_local".to_string(),
+    };
+    root.join(host).join(ident(url))
+}
+
 // Some hacks and heuristics for making equivalent URLs hash the same
 pub fn canonicalize_url(url: &Url) -> Url {
     let mut url = url.clone();
 
-    // Strip a trailing slash
+    // Strip any trailing slashes. Hosts are already lowercased when parsed.
     match url.scheme_data {
         url::SchemeData::Relative(ref mut rel) => {
-            if rel.path.last().map(|s| s.is_empty()).unwrap_or(false) {
+            while rel.path.last().map(|s| s.is_empty()).unwrap_or(false) {
                 rel.path.pop();
             }
         }
@@ -164,30 +332,13 @@ impl<'a, 'b> Registry for GitSource<'a, 'b> {
 
 impl<'a, 'b> Source for GitSource<'a, 'b> {
     fn update(&mut self) -> CargoResult<()> {
-        let actual_rev = self.remote.rev_for(&self.db_path, &self.reference);
-        let should_update = actual_rev.is_err() ||
-                            self.source_id.get_precise().is_none();
-
-        let (repo, actual_rev) = if should_update {
-            try!(self.config.shell().status("Updating",
-                format!("git repository `{}`", self.remote.get_url())));
-
-            log!(5, "updating git source `{}`", self.remote);
-            let repo = try!(self.remote.checkout(&self.db_path));
-            let rev = try!(repo.rev_for(&self.reference));
-            (repo, rev)
-        } else {
-            (try!(self.remote.db_at(&self.db_path)), actual_rev.unwrap())
-        };
-
-        try!(repo.copy_to(actual_rev.clone(), &self.checkout_path));
-
-        let source_id = self.source_id.with_precise(Some(actual_rev.to_string()));
-        let path_source = PathSource::new(&self.checkout_path, &source_id);
-
-        self.path_source = Some(path_source);
-        self.rev = Some(actual_rev);
-        self.path_source.as_mut().unwrap().update()
+        // Fetches show their progress like the shell prints everything else
+        show_progress(Some(self.config.shell().err().get_config()));
+        let ret = self.fetch_and_checkout();
+        show_progress(None);
+        // Warnings are shown even if the update fails, they may explain why.
+        try!(self.show_warnings());
+        ret
     }
 
     fn download(&mut self, _: &[PackageId]) -> CargoResult<()> {
@@ -251,6 +402,22 @@ mod test {
         assert_eq!(ident1, ident2);
     }
 
+    #[test]
+    fn test_canonicalize_idents_of_any_host() {
+        let idents = [
+            "https://example.com/foo/bar",
+            "https://example.com/foo/bar/",
+            "https://example.com/foo/bar//",
+            "https://example.com/foo/bar.git",
+            "https://example.com/foo/bar.git/",
+            "https://EXAMPLE.com/foo/bar",
+        ].iter().map(|s| ident(&url(*s))).collect::<Vec<String>>();
+        for ident in idents.iter() {
+            assert_eq!(*ident, idents[0]);
+        }
+        assert!(ident(&url("https://example.com/foo/Bar")) != idents[0]);
+    }
+
     fn url(s: &str) -> Url {
         s.to_url().unwrap()
     }
@@ -1,30 +1,496 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{mod, Show, Formatter};
-use std::io::{USER_DIR};
-use std::io::fs::{mkdir_recursive, rmdir_recursive, PathExtensions};
-use rustc_serialize::{Encodable, Encoder};
+use std::hash;
+use std::mem;
+use std::os;
+use std::io::{mod, File, IoError, USER_DIR, TempDir};
+use std::io::stdio::stderr;
+use std::io::timer;
+use std::time::Duration;
+use std::sync::TaskPool;
+use std::sync::atomic;
+use std::io::fs::{mod, mkdir_recursive, rmdir_recursive, PathExtensions};
+use std::str::FromStr;
+use rustc_serialize::{Encodable, Encoder, Decodable, Decoder};
+use rustc_serialize::base64::FromBase64;
+use glob::Pattern;
+use term::color::GREEN;
+use time;
 use url::Url;
 use git2;
 
-use core::GitReference;
-use util::{CargoResult, ChainError, human, ToUrl, internal, Require};
+use core::{GitReference, Shell, ShellConfig};
+use sources::git::source::{checkouts_dir, canonicalize_url};
+use util::{CargoResult, CargoError, ChainError, FromError, human, ToUrl, internal};
+use util::{Require, ProcessBuilder, ProcessError, FileLock, Sha1, process, to_hex};
+use util::config;
 
-#[deriving(PartialEq, Clone)]
+/// The maximum depth of nested submodules which will be updated before giving
+/// up on a checkout.
+const MAX_SUBMODULE_DEPTH: uint = 10;
+
+/// The number of credentials handed out during a single fetch before giving up,
+/// which is enough for a credential provider, ssh-agent and every ssh key tried.
+const MAX_AUTH_ATTEMPTS: uint = 5;
+
+/// The length git abbreviates commit hashes to by default.
+const SHORT_REV_LEN: uint = 7;
+
+/// The refspec fetching all tags. It's forced so that tags which were moved
+/// upstream are moved locally as well.
+const TAGS_REFSPEC: &'static str = "+refs/tags/*:refs/tags/*";
+
+/// The url schemes which libgit2 knows how to fetch from.
+const SUPPORTED_SCHEMES: &'static [&'static str] = &["http", "https", "ssh",
+                                                     "git", "file"];
+
+// What all fetches transferred so far, across all threads as submodules are
+// fetched in parallel.
+static FETCHED_REPOSITORIES: atomic::AtomicUint = atomic::INIT_ATOMIC_UINT;
+static FETCHED_OBJECTS: atomic::AtomicUint = atomic::INIT_ATOMIC_UINT;
+static FETCHED_BYTES: atomic::AtomicUint = atomic::INIT_ATOMIC_UINT;
+
+// Whether problems with ssh-agent were already warned about.
+static WARNED_SSH_AGENT: atomic::AtomicBool = atomic::INIT_ATOMIC_BOOL;
+
+// The `git://` urls which were already warned about.
+thread_local!(static WARNED_GIT_PROTOCOL: RefCell<HashSet<String>>
+              = RefCell::new(HashSet::new()));
+
+// Warnings about git operations on this thread which haven't been shown yet,
+// they're handed to whoever is reporting to the user through `take_warnings`.
+thread_local!(static WARNINGS: RefCell<Vec<String>> = RefCell::new(Vec::new()))
+
+// How the progress of fetches on this thread is printed, if at all. It's set
+// through `show_progress` by whoever is reporting to the user.
+thread_local!(static PROGRESS: Cell<Option<ShellConfig>> = Cell::new(None))
+
+// Revisions which references have already been resolved to in each database,
+// to avoid walking the object database over and over again when the same
+// reference is resolved repeatedly. Entries for a database must be invalidated
+// whenever it's fetched into, as branches may have moved.
+thread_local!(static REV_CACHE: RefCell<HashMap<(Path, GitReference), GitRevision>>
+              = RefCell::new(HashMap::new()));
+
+// The git configuration which was read for each working directory, so the
+// configuration files are only read and parsed once.
+thread_local!(static CONFIG_CACHE: RefCell<HashMap<Path, GitConfig>>
+              = RefCell::new(HashMap::new()));
+
+/// Configuration of git operations, read from the `[git]` table of cargo's
+/// configuration files.
+#[deriving(Clone)]
+pub struct GitConfig {
+    /// The number of times a fetch is retried after a network error.
+    pub retries: uint,
+    /// Fingerprints of the GPG keys which tags must be signed with. Tags
+    /// aren't verified at all if this is empty.
+    pub signing_keys: Vec<String>,
+    /// A private SSH key to authenticate with when ssh-agent isn't available
+    /// or doesn't have the right key.
+    pub ssh_key: Option<Path>,
+    /// Whether to fetch by running the `git` binary instead of using libgit2.
+    pub fetch_with_cli: bool,
+    /// Whether tags and branches which can't be found are looked for again
+    /// with their case ignored.
+    pub case_insensitive_refs: bool,
+    /// The number of submodules of a checkout which are updated at once.
+    pub submodule_jobs: uint,
+    /// Refspecs to fetch from particular urls on top of the usual branches and
+    /// tags, such as the `refs/changes/*` of Gerrit.
+    pub refspecs: HashMap<String, Vec<String>>,
+    /// Local repositories, such as pre-warmed mirrors, which new databases
+    /// borrow objects from instead of downloading them again.
+    pub alternates: Vec<Path>,
+    /// The directories which checkouts of particular urls are limited to, for
+    /// crates living in a corner of a large repository.
+    pub sparse_paths: HashMap<String, Vec<String>>,
+    /// Whether the submodules of checkouts are checked out at all.
+    pub submodules: bool,
+    /// Glob patterns of the paths of submodules which aren't checked out.
+    pub skip_submodules: Vec<String>,
+    /// The `User-Agent` to send when fetching over http(s).
+    pub user_agent: Option<String>,
+    /// Extra headers, such as `X-Token: abc`, to send when fetching over
+    /// http(s).
+    pub http_headers: Vec<String>,
+    /// The proxy of cargo's own `http.proxy`, which takes precedence over the
+    /// proxy git is configured with.
+    pub http_proxy: Option<String>,
+    /// The branch which git dependencies without a `branch`, `tag` or `rev`
+    /// are resolved against, or the one the remote's `HEAD` points at (falling
+    /// back to `master`) if `None`.
+    pub default_branch: Option<String>,
+    /// Whether SSH hosts which aren't in `~/.ssh/known_hosts` yet are trusted
+    /// on first connection, rather than refused. They are by default, while
+    /// hosts which are in there always have to present the recorded key.
+    pub accept_new_host_keys: bool,
+    /// Where new databases and checkouts are cloned into before being moved
+    /// into place, next to where they end up if `None`.
+    pub staging_dir: Option<Path>,
+    /// Whether repositories with `git://` urls are refused rather than just
+    /// warned about.
+    pub deny_git_protocol: bool,
+    /// How many commits of history new databases for tags get, or all of it
+    /// if `None`.
+    pub tag_depth: Option<uint>,
+    /// How many commits of history new databases for branches get, or all of
+    /// it if `None`.
+    pub branch_depth: Option<uint>,
+    /// Whether files which git doesn't track are deleted from checkouts when
+    /// they're reset to another revision.
+    pub clean_untracked: bool,
+}
+
+impl GitConfig {
+    /// Returns how many commits of history a new database for `reference`
+    /// should be cloned with, or `None` for all of it.
+    pub fn depth(&self, reference: &GitReference) -> Option<uint> {
+        match *reference {
+            GitReference::Tag(..) => self.tag_depth,
+            GitReference::Branch(..) => self.branch_depth,
+            // A revision can be anywhere in the history, and there's no way of
+            // knowing how deep short of fetching all of it.
+            GitReference::Rev(..) => None,
+        }
+    }
+}
+
+/// Returns the git configuration for the current working directory. It's only
+/// read from the configuration files the first time.
+pub fn git_configuration() -> CargoResult<GitConfig> {
+    let cwd = try!(os::getcwd());
+    let cached = CONFIG_CACHE.with(|cache| {
+        cache.borrow().get(&cwd).map(|config| config.clone())
+    });
+    match cached {
+        Some(config) => return Ok(config),
+        None => {}
+    }
+    let config = try!(read_git_configuration(&cwd));
+    CONFIG_CACHE.with(|cache| cache.borrow_mut().insert(cwd, config.clone()));
+    Ok(config)
+}
+
+fn read_git_configuration(cwd: &Path) -> CargoResult<GitConfig> {
+    let mut ret = GitConfig {
+        retries: 2,
+        signing_keys: Vec::new(),
+        ssh_key: None,
+        fetch_with_cli: false,
+        case_insensitive_refs: false,
+        submodule_jobs: os::num_cpus(),
+        refspecs: HashMap::new(),
+        alternates: Vec::new(),
+        sparse_paths: HashMap::new(),
+        submodules: true,
+        skip_submodules: Vec::new(),
+        user_agent: None,
+        http_headers: Vec::new(),
+        http_proxy: None,
+        default_branch: None,
+        accept_new_host_keys: true,
+        staging_dir: None,
+        deny_git_protocol: false,
+        tag_depth: None,
+        branch_depth: None,
+        clean_untracked: false,
+    };
+    let configs = try!(config::all_configs(cwd.clone()));
+    match configs.get("http") {
+        Some(http) => {
+            let http = try!(http.table().chain_error(|| {
+                internal("invalid configuration for the key `http`")
+            }));
+            match http.get("proxy") {
+                Some(proxy) => {
+                    ret.http_proxy = Some(try!(proxy.string().chain_error(|| {
+                        internal("invalid configuration for key `http.proxy`")
+                    })).0.to_string());
+                }
+                None => {}
+            }
+        }
+        None => {}
+    }
+    let git = match configs.get("git") {
+        None => return Ok(ret),
+        Some(git) => try!(git.table().chain_error(|| {
+            internal("invalid configuration for the key `git`")
+        })),
+    };
+    for (k, v) in git.iter() {
+        let invalid = || {
+            internal(format!("invalid configuration for key `git.{}`", k))
+        };
+        match k.as_slice() {
+            "retry" => {
+                let (retry, _) = try!(v.integer().chain_error(invalid));
+                if retry < 0 {
+                    return Err(human("`git.retry` cannot be negative"))
+                }
+                ret.retries = retry as uint;
+            }
+            "allowed-signing-keys" => {
+                let keys = try!(v.list().chain_error(invalid));
+                ret.signing_keys = Vec::new();
+                for &(ref key, _) in keys.iter() {
+                    ret.signing_keys.push(try!(signing_key(key.as_slice())));
+                }
+            }
+            "ssh-key" => {
+                let (key, path) = try!(v.string().chain_error(invalid));
+                // Relative paths are relative to the directory containing the
+                // `.cargo` folder the key was configured in.
+                ret.ssh_key = Some(path.dir_path().dir_path().join(key));
+            }
+            "accept-new-host-keys" => {
+                let (accept, _) = try!(v.boolean().chain_error(invalid));
+                ret.accept_new_host_keys = accept;
+            }
+            "fetch-with-cli" => {
+                ret.fetch_with_cli = try!(v.boolean().chain_error(invalid)).0;
+            }
+            "case-insensitive-refs" => {
+                let (ignore_case, _) = try!(v.boolean().chain_error(invalid));
+                ret.case_insensitive_refs = ignore_case;
+            }
+            "submodule-jobs" => {
+                let (jobs, _) = try!(v.integer().chain_error(invalid));
+                if jobs < 1 {
+                    return Err(human("`git.submodule-jobs` must be at least 1"))
+                }
+                ret.submodule_jobs = jobs as uint;
+            }
+            "refspecs" => {
+                let table = try!(v.table().chain_error(invalid));
+                ret.refspecs = try!(url_lists(k.as_slice(), table));
+            }
+            "sparse-paths" => {
+                let table = try!(v.table().chain_error(invalid));
+                ret.sparse_paths = try!(url_lists(k.as_slice(), table));
+            }
+            "submodules" => {
+                ret.submodules = try!(v.boolean().chain_error(invalid)).0;
+            }
+            "skip-submodules" => {
+                let patterns = try!(v.list().chain_error(invalid));
+                ret.skip_submodules = patterns.iter().map(|&(ref p, _)| {
+                    p.clone()
+                }).collect();
+            }
+            "user-agent" => {
+                ret.user_agent = Some(try!(v.string().chain_error(invalid)).0
+                                            .to_string());
+            }
+            "default-branch" => {
+                let (branch, _) = try!(v.string().chain_error(invalid));
+                if branch.is_empty() {
+                    return Err(human("`git.default-branch` can't be empty"))
+                }
+                ret.default_branch = Some(branch.to_string());
+            }
+            "http-headers" => {
+                let headers = try!(v.list().chain_error(invalid));
+                ret.http_headers = headers.iter().map(|&(ref h, _)| {
+                    h.clone()
+                }).collect();
+            }
+            "alternates" => {
+                let paths = try!(v.list().chain_error(invalid));
+                // Like `ssh-key`, relative paths are relative to the directory
+                // containing the `.cargo` folder.
+                ret.alternates = paths.iter().map(|&(ref alternate, ref path)| {
+                    path.dir_path().dir_path().join(alternate.as_slice())
+                }).collect();
+            }
+            "deny-git-protocol" => {
+                let (deny, _) = try!(v.boolean().chain_error(invalid));
+                ret.deny_git_protocol = deny;
+            }
+            "clean-untracked" => {
+                let (clean, _) = try!(v.boolean().chain_error(invalid));
+                ret.clean_untracked = clean;
+            }
+            "tag-depth" | "branch-depth" => {
+                let (depth, _) = try!(v.integer().chain_error(invalid));
+                if depth <= 0 {
+                    return Err(human(format!("`git.{}` has to be at least 1", k)))
+                }
+                if k.as_slice() == "tag-depth" {
+                    ret.tag_depth = Some(depth as uint);
+                } else {
+                    ret.branch_depth = Some(depth as uint);
+                }
+            }
+            "staging-dir" => {
+                let (dir, path) = try!(v.string().chain_error(invalid));
+                ret.staging_dir = Some(path.dir_path().dir_path().join(dir));
+            }
+            _ => {}
+        }
+    }
+    Ok(ret)
+}
+
+/// Normalizes a key of `git.allowed-signing-keys` to the uppercase hex digits
+/// of its fingerprint, with any spaces removed.
+///
+/// Fingerprints are matched by their end, so short key IDs are refused: those
+/// are easily forged, and would let any key which happens to end with the same
+/// eight digits through.
+fn signing_key(key: &str) -> CargoResult<String> {
+    let key = key.chars().filter(|c| !c.is_whitespace())
+                 .map(|c| c.to_uppercase()).collect::<String>();
+    let hex = key.as_slice().chars().all(|c| c.to_digit(16).is_some());
+    if !hex || key.len() < 16 {
+        return Err(human(format!("`git.allowed-signing-keys` has to list full \
+                                  fingerprints or long key IDs (16 hex digits), \
+                                  which `{}` isn't", key)))
+    }
+    Ok(key)
+}
+
+/// Reads a table of the configuration `git.<key>` which maps urls to lists of
+/// strings. Trailing slashes are stripped from the urls.
+fn url_lists(key: &str, table: &HashMap<String, config::ConfigValue>)
+             -> CargoResult<HashMap<String, Vec<String>>> {
+    let mut ret = HashMap::new();
+    for (url, list) in table.iter() {
+        let list = try!(list.list().chain_error(|| {
+            internal(format!("invalid configuration for key `git.{}.{}`",
+                             key, url))
+        }));
+        ret.insert(url.as_slice().trim_right_chars('/').to_string(),
+                   list.iter().map(|&(ref s, _)| s.clone()).collect());
+    }
+    Ok(ret)
+}
+
+impl GitConfig {
+    /// Returns the refspecs configured to be fetched from `url` on top of the
+    /// usual ones.
+    pub fn extra_refspecs(&self, url: &str) -> &[String] {
+        match self.refspecs.get(url.trim_right_chars('/')) {
+            Some(refspecs) => refspecs.as_slice(),
+            None => &[],
+        }
+    }
+
+    /// Returns the directories checkouts of `url` are limited to, all of the
+    /// repository being checked out if there are none.
+    pub fn sparse_paths(&self, url: &str) -> &[String] {
+        match self.sparse_paths.get(url.trim_right_chars('/')) {
+            Some(paths) => paths.as_slice(),
+            None => &[],
+        }
+    }
+
+    /// Returns the git configuration keys and values which set the configured
+    /// `User-Agent` and headers for fetches of `url`. These only apply to
+    /// http(s) urls.
+    fn http_options(&self, url: &str) -> Vec<(String, String)> {
+        let mut ret = Vec::new();
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return ret
+        }
+        for agent in self.user_agent.iter() {
+            ret.push(("http.userAgent".to_string(), agent.clone()));
+        }
+        for header in self.http_headers.iter() {
+            ret.push(("http.extraHeader".to_string(), header.clone()));
+        }
+        // git only knows about its own proxy settings
+        if !no_proxy(url) {
+            for proxy in self.http_proxy.iter() {
+                ret.push(("http.proxy".to_string(), proxy.clone()));
+            }
+        }
+        ret
+    }
+}
+
+#[deriving(PartialEq, Eq, Clone)]
 #[allow(missing_copy_implementations)]
 pub struct GitRevision(git2::Oid);
 
+impl<S: hash::Writer> hash::Hash<S> for GitRevision {
+    fn hash(&self, into: &mut S) {
+        self.0.as_bytes().hash(into)
+    }
+}
+
 impl Show for GitRevision {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         self.0.fmt(f)
     }
 }
 
+/// Revisions are encoded as their full 40 character hex hash.
+impl<E, S: Encoder<E>> Encodable<S, E> for GitRevision {
+    fn encode(&self, s: &mut S) -> Result<(), E> {
+        self.to_string().encode(s)
+    }
+}
+
+impl<E, D: Decoder<E>> Decodable<D, E> for GitRevision {
+    fn decode(d: &mut D) -> Result<GitRevision, E> {
+        let s = raw_try!(d.read_str());
+        match from_str(s.as_slice()) {
+            Some(rev) => Ok(rev),
+            None => Err(d.error(format!("invalid git revision `{}`",
+                                        s).as_slice())),
+        }
+    }
+}
+
+/// Parses the full 40 character hex hash of a revision. Abbreviated hashes
+/// aren't accepted as they can only be expanded with a repository at hand.
+impl FromStr for GitRevision {
+    fn from_str(s: &str) -> Option<GitRevision> {
+        if s.len() != 40 || !s.chars().all(|c| c.to_digit(16).is_some()) {
+            return None
+        }
+        git2::Oid::from_str(s).ok().map(GitRevision)
+    }
+}
+
+impl GitRevision {
+    /// Returns the hash of this revision abbreviated to 7 characters, like
+    /// git does by default.
+    ///
+    /// The abbreviation may be ambiguous, see `GitDatabase::short_rev` for
+    /// one which isn't.
+    pub fn short(&self) -> String {
+        self.to_string().as_slice().slice_to(SHORT_REV_LEN).to_string()
+    }
+}
+
 /// GitRemote represents a remote repository. It gets cloned into a local
 /// GitDatabase.
-#[deriving(PartialEq,Clone,Show)]
+#[deriving(Clone,Show)]
 pub struct GitRemote {
     url: Url,
 }
 
+// Remotes are compared by their canonical urls, so that two spellings of the
+// url of the same repository are the same remote.
+impl PartialEq for GitRemote {
+    fn eq(&self, other: &GitRemote) -> bool {
+        self.url == other.url ||
+            canonicalize_url(&self.url) == canonicalize_url(&other.url)
+    }
+}
+
+impl Eq for GitRemote {}
+
+impl<S: hash::Writer> hash::Hash<S> for GitRemote {
+    fn hash(&self, into: &mut S) {
+        canonicalize_url(&self.url).hash(into)
+    }
+}
+
 #[deriving(PartialEq,Clone,RustcEncodable)]
 struct EncodableGitRemote {
     url: String,
@@ -75,14 +541,14 @@ pub struct GitCheckout<'a> {
 pub struct EncodableGitCheckout {
     database: EncodableGitDatabase,
     location: String,
-    revision: String,
+    revision: GitRevision,
 }
 
 impl<'a, E, S: Encoder<E>> Encodable<S, E> for GitCheckout<'a> {
     fn encode(&self, s: &mut S) -> Result<(), E> {
         EncodableGitCheckout {
             location: self.location.display().to_string(),
-            revision: self.revision.to_string(),
+            revision: self.revision.clone(),
             database: EncodableGitDatabase {
                 remote: self.database.remote.clone(),
                 path: self.database.path.display().to_string(),
@@ -91,8 +557,244 @@ impl<'a, E, S: Encoder<E>> Encodable<S, E> for GitCheckout<'a> {
     }
 }
 
+/// A handle on a `GitCheckout` which can be sent to other threads, unlike the
+/// checkout itself as repositories can't be shared between threads. Each
+/// thread opens the checkout's repository for itself with `open`.
+#[deriving(Clone)]
+pub struct GitCheckoutHandle {
+    location: Path,
+    revision: GitRevision,
+}
+
+impl GitCheckoutHandle {
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    pub fn revision(&self) -> &GitRevision {
+        &self.revision
+    }
+
+    /// Opens the repository of the checkout, failing if it has since been
+    /// moved off the revision it was at.
+    pub fn open(&self) -> CargoResult<git2::Repository> {
+        let repo = try!(git2::Repository::open(&self.location).chain_error(|| {
+            human(format!("no checkout at {}", self.location.display()))
+        }));
+        let head = repo.head().ok().and_then(|head| head.target());
+        if head != Some(self.revision.0) {
+            return Err(human(format!("the checkout at {} is no longer at \
+                                      revision `{}`", self.location.display(),
+                                     self.revision)))
+        }
+        Ok(repo)
+    }
+}
+
+/// The state of a checkout relative to the revision it should be at.
+#[deriving(PartialEq, Clone, Show)]
+pub enum CheckoutState {
+    /// The checkout is already at the revision.
+    Fresh,
+    /// The checkout exists, but needs to be reset from the first revision (if
+    /// it has one) to the second.
+    NeedsReset(Option<GitRevision>, GitRevision),
+    /// There is no checkout yet.
+    NeedsClone,
+}
+
+/// How a file differs between two revisions, as listed by `GitDatabase::diff`.
+#[deriving(PartialEq, Eq, PartialOrd, Ord, Clone, Show, Copy)]
+pub enum ChangeKind {
+    /// The file is only in the later revision.
+    Added,
+    /// The file is only in the earlier revision.
+    Deleted,
+    /// The file is in both, with different contents.
+    Modified,
+}
+
+/// How much was transferred by a fetch, as reported by libgit2 once the fetch
+/// finished.
+#[deriving(PartialEq, Clone, Show, Copy)]
+pub struct FetchStats {
+    /// The number of objects downloaded from the remote.
+    pub received_objects: uint,
+    /// The number of objects the remote had to send.
+    pub total_objects: uint,
+    /// The number of bytes downloaded from the remote.
+    pub received_bytes: uint,
+    /// The number of objects which were already present locally.
+    pub local_objects: uint,
+}
+
+/// How much all the fetches of this process transferred between them, as
+/// returned by `fetch_totals`.
+#[deriving(PartialEq, Clone, Copy)]
+pub struct FetchTotals {
+    /// The number of fetches of git repositories, including their submodules.
+    pub repositories: uint,
+    /// The number of objects downloaded, which is only known for fetches done
+    /// by libgit2 rather than the `git` binary (as are the bytes).
+    pub received_objects: uint,
+    /// The number of bytes downloaded.
+    pub received_bytes: uint,
+}
+
+impl Show for FetchTotals {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let repositories = if self.repositories == 1 {
+            "repository"
+        } else {
+            "repositories"
+        };
+        try!(write!(f, "{} git {}, ", self.repositories, repositories));
+        if self.received_bytes >= 1024 * 1024 {
+            write!(f, "{:.1} MiB", self.received_bytes as f64 / (1024.0 * 1024.0))
+        } else {
+            write!(f, "{} KiB", self.received_bytes / 1024)
+        }
+    }
+}
+
+/// What `GitDatabase::copy_to` had to do to bring a checkout up to date.
+#[deriving(PartialEq, Clone, Show, Copy)]
+pub enum CheckoutUpdate {
+    /// The checkout was already at the revision.
+    Fresh,
+    /// The checkout already had the revision, and only needed resetting to it.
+    Reset,
+    /// The revision was fetched into an existing checkout.
+    Fetched,
+    /// A new checkout was cloned.
+    Cloned,
+}
+
+impl CheckoutUpdate {
+    /// Whether objects had to be fetched for the checkout.
+    pub fn fetched(&self) -> bool {
+        match *self {
+            CheckoutUpdate::Fetched | CheckoutUpdate::Cloned => true,
+            CheckoutUpdate::Fresh | CheckoutUpdate::Reset => false,
+        }
+    }
+}
+
+pub type GitResult<T> = Result<T, GitError>;
+
+/// An error from a git operation, along with the kind of failure it is so
+/// callers can react to it without inspecting its message.
+///
+/// It converts into any `CargoResult`, and is displayed just like the error it
+/// wraps.
+pub struct GitError {
+    kind: GitErrorKind,
+    error: Box<CargoError>,
+}
+
+#[deriving(PartialEq, Eq, Clone, Show, Copy)]
+pub enum GitErrorKind {
+    /// A revision or reference couldn't be found.
+    NotFound,
+    /// The remote rejected all of the credentials which were tried.
+    Auth,
+    /// Talking to the remote failed, which may go away if tried again.
+    Network,
+    /// A reference names more than one revision.
+    Ambiguous,
+    /// The repository on disk is damaged.
+    Corrupt,
+    /// Anything else.
+    Other,
+}
+
+/// A source of credentials for fetches which is consulted before any of the
+/// usual ones (ssh-agent, ssh keys and credential helpers), for tools which
+/// mint credentials of their own.
+///
+/// Only `fetch_with_credentials` takes one; git sources always authenticate
+/// with the usual credentials. The `git` binary can't be handed credentials,
+/// so fetches which would be done with it fail rather than going ahead
+/// without the provider's.
+pub trait CredentialProvider {
+    /// Returns the credentials to authenticate to `url` with, if there are any
+    /// for it. `username` and `allowed` are what the server asked for.
+    fn credentials(&mut self, url: &str, username: Option<&str>,
+                   allowed: git2::CredentialType) -> Option<git2::Cred>;
+}
+
+/// The provider used when no credentials are provided from the outside.
+struct NoCredentials;
+
+impl CredentialProvider for NoCredentials {
+    fn credentials(&mut self, _url: &str, _username: Option<&str>,
+                   _allowed: git2::CredentialType) -> Option<git2::Cred> {
+        None
+    }
+}
+
 // Implementations
 
+impl GitError {
+    pub fn new(kind: GitErrorKind, error: Box<CargoError>) -> GitError {
+        GitError { kind: kind, error: error }
+    }
+
+    pub fn kind(&self) -> GitErrorKind {
+        self.kind
+    }
+
+    /// Wraps this error in `error`, keeping the kind of the original.
+    fn context(self, error: Box<CargoError>) -> GitError {
+        GitError {
+            kind: self.kind,
+            error: error.concrete().with_cause(self.error),
+        }
+    }
+}
+
+impl Show for GitError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl CargoError for GitError {
+    fn description(&self) -> String { self.error.description() }
+    fn detail(&self) -> Option<String> { self.error.detail() }
+    fn cause(&self) -> Option<&CargoError> { self.error.cause() }
+    fn is_human(&self) -> bool { self.error.is_human() }
+}
+
+impl FromError<GitError> for GitError {
+    fn from_error(error: GitError) -> GitError { error }
+}
+
+impl FromError<Box<CargoError>> for GitError {
+    fn from_error(error: Box<CargoError>) -> GitError {
+        GitError::new(GitErrorKind::Other, error)
+    }
+}
+
+impl FromError<IoError> for GitError {
+    fn from_error(error: IoError) -> GitError {
+        GitError::new(GitErrorKind::Other, box error)
+    }
+}
+
+impl FromError<git2::Error> for GitError {
+    fn from_error(error: git2::Error) -> GitError {
+        let kind = match error.code() {
+            git2::ErrorCode::NotFound => GitErrorKind::NotFound,
+            git2::ErrorCode::Ambiguous => GitErrorKind::Ambiguous,
+            _ if is_network_error(&error) => GitErrorKind::Network,
+            _ if is_corruption(&error) => GitErrorKind::Corrupt,
+            _ => GitErrorKind::Other,
+        };
+        GitError::new(kind, box error)
+    }
+}
+
 impl GitRemote {
     pub fn new(url: &Url) -> GitRemote {
         GitRemote { url: url.clone() }
@@ -103,31 +805,316 @@ impl GitRemote {
     }
 
     pub fn rev_for(&self, path: &Path, reference: &GitReference)
-                   -> CargoResult<GitRevision> {
+                   -> GitResult<GitRevision> {
         let db = try!(self.db_at(path));
         db.rev_for(reference)
     }
 
-    pub fn checkout(&self, into: &Path) -> CargoResult<GitDatabase> {
-        let repo = match git2::Repository::open(into) {
-            Ok(repo) => {
-                try!(self.fetch_into(&repo).chain_error(|| {
-                    internal(format!("failed to fetch into {}", into.display()))
-                }));
-                repo
+    /// Updates the database at `into` with the contents of the remote,
+    /// creating it if necessary.
+    ///
+    /// Only the branch or tag named by `reference` is fetched where possible,
+    /// falling back to all branches for a `GitReference::Rev` as there's no
+    /// way of knowing in advance which branch contains the revision.
+    ///
+    /// A new database is seeded with the objects of the repositories configured
+    /// in `git.alternates`. Otherwise it only gets as much history as
+    /// `git.tag-depth` or `git.branch-depth` allow for `reference`.
+    pub fn checkout(&self, into: &Path, reference: &GitReference)
+                    -> GitResult<GitDatabase> {
+        let config = try!(git_configuration());
+        self.checkout_with_alternates(into, reference,
+                                      config.alternates.as_slice())
+    }
+
+    /// Same as `checkout`, but a new database borrows the objects of the local
+    /// repositories at `alternates` (through git's `objects/info/alternates`)
+    /// so only what they're missing is downloaded.
+    ///
+    /// Once cloned, the database gets copies of the borrowed objects it needs
+    /// and stops borrowing, like `git clone --dissociate`, as it would break
+    /// whenever an alternate is deleted, cloned again or garbage collected.
+    /// Databases which still borrow objects (as made by older versions of
+    /// cargo) are dissociated when they're next updated, and cloned again if
+    /// that fails because their alternates already lost some of the objects.
+    pub fn checkout_with_alternates(&self, into: &Path, reference: &GitReference,
+                                    alternates: &[Path])
+                                    -> GitResult<GitDatabase> {
+        try!(self.check_scheme());
+        if offline() {
+            return self.checkout_offline(into, reference).map_err(|e| {
+                GitError::new(GitErrorKind::NotFound, e)
+            })
+        }
+
+        // Another cargo process may be updating the same database, so wait for
+        // it to finish before touching anything.
+        let _lock = try!(RepositoryLock::acquire(into));
+
+        let refspec = refspec(reference);
+        invalidate_rev_cache(into);
+
+        // A database whose configuration was lost can't even be opened, but
+        // that's simple enough to put back.
+        if into.join("objects").is_dir() {
+            try!(repair_config(into, true));
+        }
+
+        let dissociated = match dissociate(into) {
+            Ok(()) => true,
+            Err(e) => {
+                info!("database at {} lost objects of its alternates, cloning \
+                       it again: {}", into.display(), e);
+                false
             }
-            Err(..) => {
-                try!(self.clone_into(into).chain_error(|| {
-                    internal(format!("failed to clone into: {}", into.display()))
+        };
+
+        // A database which was left broken behind, say by a fetch which was
+        // interrupted halfway, is thrown away and cloned again from scratch.
+        let existing = match git2::Repository::open(into) {
+            Ok(..) if !dissociated => None,
+            Ok(ref repo) if !is_intact(repo) => {
+                info!("database at {} is broken, cloning it again",
+                      into.display());
+                None
+            }
+            Ok(repo) => Some(repo),
+            Err(..) => None,
+        };
+        let existing = match existing {
+            Some(repo) => {
+                let old_tag = tag_target(&repo, reference);
+                let fetched = if repo.path().join("shallow").exists() {
+                    let depth = try!(git_configuration()).depth(reference);
+                    self.fetch_into_shallow(&repo, refspec.as_slice(), depth)
+                } else {
+                    self.fetch_into(&repo, refspec.as_slice())
+                };
+                match fetched {
+                    Ok(()) => {
+                        match (old_tag, tag_target(&repo, reference)) {
+                            (Some(ref old), Some(ref new)) if old != new => {
+                                warn(format!("tag `{}` of {} was moved \
+                                              upstream from {} to {}, using \
+                                              its new target",
+                                             reference_name(reference),
+                                             self.url, old.short(),
+                                             new.short()));
+                            }
+                            _ => {}
+                        }
+                        Some(repo)
+                    }
+                    Err(ref e) if e.kind() == GitErrorKind::Corrupt => {
+                        info!("database at {} is corrupt, cloning it again: {}",
+                              into.display(), e.description());
+                        None
+                    }
+                    Err(e) => {
+                        return Err(e.context(internal(format!(
+                            "failed to fetch `{}` into {}", self.url,
+                            into.display()))))
+                    }
+                }
+            }
+            None => None,
+        };
+        let repo = match existing {
+            Some(repo) => repo,
+            None => {
+                try!(self.preflight(reference));
+                let depth = try!(git_configuration()).depth(reference);
+                let ret = match depth {
+                    // Objects borrowed from alternates cost nothing to
+                    // download, so there's no point in cutting off the history
+                    // then.
+                    Some(depth) if alternates.len() == 0 => {
+                        let option = format!("--depth={}", depth);
+                        self.shallow_clone(into, refspec.as_slice(),
+                                           option.as_slice())
+                            .map(|db| db.repo).map_err(FromError::from_error)
+                    }
+                    _ => self.clone_into(into, refspec.as_slice(), alternates),
+                };
+                try!(ret.map_err(|e| {
+                    e.context(internal(format!("failed to clone `{}` into {}",
+                                               self.url, into.display())))
                 }))
             }
         };
 
-        Ok(GitDatabase { remote: self.clone(), path: into.clone(), repo: repo })
+        let db = GitDatabase { remote: self.clone(), path: into.clone(), repo: repo };
+        try!(db.fetch_unadvertised(reference));
+        Ok(db)
+    }
+
+    /// Lists the references advertised by this remote along with the revisions
+    /// they point to, without fetching any objects.
+    pub fn ls_remote(&self) -> CargoResult<Vec<(String, GitRevision)>> {
+        try!(self.check_scheme());
+        // Anonymous remotes need a repository to live in, so create a
+        // throwaway one which is deleted once we're done.
+        let tmp = try!(TempDir::new("cargo-ls-remote"));
+        let repo = try!(git2::Repository::init_bare(tmp.path()));
+        let cfg = try!(repo.config());
+        let url = rewrite_url(&cfg, self.url.to_string().as_slice());
+        let config = try!(git_configuration());
+        with_authentication(url.as_slice(), &[&cfg], &mut NoCredentials, |f| {
+            let mut host_key = HostKeyCheck::new(url.as_slice(), &config);
+            let ret = {
+                let mut cb = git2::RemoteCallbacks::new()
+                                               .credentials(f);
+                match host_key {
+                    Some(ref mut check) => {
+                        cb = cb.certificate_check(|cert, _host| check.check(cert));
+                    }
+                    None => {}
+                }
+                let mut remote = try!(repo.remote_anonymous(url.as_slice(),
+                                                            "refs/heads/*:refs/heads/*"));
+                remote.set_callbacks(&mut cb);
+                remote.connect(git2::Direction::Fetch).and_then(|()| {
+                    let refs = try!(remote.list()).iter().map(|head| {
+                        (head.name().to_string(), GitRevision(head.oid()))
+                    }).collect::<Vec<(String, GitRevision)>>();
+                    remote.disconnect();
+                    Ok(refs)
+                })
+            };
+            match refused_host_key(host_key) {
+                Some(err) => Err(err),
+                None => Ok(try!(ret)),
+            }
+        }).chain_error(|| {
+            human(format!("failed to list the references of `{}`", self.url))
+        })
+    }
+
+    /// Returns the name of the branch that the remote's `HEAD` points at, which
+    /// is the branch a plain `git clone` would check out.
+    ///
+    /// libgit2 only hands out the commit `HEAD` points at, so this asks
+    /// `git ls-remote --symref` for the branch instead, reaching the remote the
+    /// same way fetches do. `None` is returned if the remote doesn't say, as
+    /// when its `HEAD` is detached.
+    pub fn default_branch(&self) -> CargoResult<Option<String>> {
+        try!(self.check_scheme());
+        let config = try!(git_configuration());
+        let cfg = try!(git2::Config::open_default());
+        let url = rewrite_url(&cfg, self.url.to_string().as_slice());
+        let url = url.as_slice();
+        let mut cmd = try!(git_with_options(config.http_options(url).as_slice()));
+        match ssh_command(&cfg, &config, url) {
+            Some(ssh) => cmd = cmd.env("GIT_SSH_COMMAND", Some(ssh.as_slice())),
+            None => {}
+        }
+        let output = try!(cmd.arg("ls-remote").arg("--symref").arg(url).arg("HEAD")
+                             .exec_with_output().chain_error(|| {
+            human(format!("failed to find the default branch of `{}`", self.url))
+        }));
+        let listing = String::from_utf8_lossy(output.output.as_slice()).into_owned();
+        Ok(head_branch(listing.as_slice()))
+    }
+
+    /// Fails with a clear error if the url's scheme isn't one git can fetch
+    /// from, rather than some cryptic transport error halfway into a fetch.
+    ///
+    /// `git://` urls are warned about (once), as the protocol is neither
+    /// authenticated nor encrypted, or refused with `git.deny-git-protocol`.
+    fn check_scheme(&self) -> CargoResult<()> {
+        let scheme = self.url.scheme.as_slice();
+        if scheme == "git" {
+            return self.check_git_protocol()
+        }
+        if SUPPORTED_SCHEMES.iter().any(|s| *s == scheme) {
+            return Ok(())
+        }
+        Err(human(format!("unsupported url scheme `{}` in git repository `{}`\n\
+                           supported schemes are {}", scheme, self.url,
+                          SUPPORTED_SCHEMES.connect(", "))))
+    }
+
+    fn check_git_protocol(&self) -> CargoResult<()> {
+        if try!(git_configuration()).deny_git_protocol {
+            return Err(human(format!("refusing to fetch `{}` over the insecure \
+                                      `git://` protocol, as configured with \
+                                      `git.deny-git-protocol`; use an https:// \
+                                      or ssh:// url instead", self.url)))
+        }
+        let url = self.url.to_string();
+        let first = WARNED_GIT_PROTOCOL.with(|warned| {
+            warned.borrow_mut().insert(url.clone())
+        });
+        if first {
+            warn(format!("`{}` is fetched over the `git://` protocol, which \
+                          is insecure and being turned off by many hosts; \
+                          consider an https:// or ssh:// url instead", url));
+        }
+        Ok(())
+    }
+
+    /// Checks that the branch, tag or full refname named by `reference` is
+    /// advertised by the remote before a clone starts, so a reference which
+    /// has gone away fails right away instead of at the end of a long download.
+    ///
+    /// Nothing is checked when the fetch is going to be done by the `git`
+    /// binary (see `uses_cli`), as libgit2 may not be able to connect to the
+    /// remote the way git will.
+    fn preflight(&self, reference: &GitReference) -> GitResult<()> {
+        let (kind, refname) = match *reference {
+            GitReference::Branch(ref s) => ("branch", format!("refs/heads/{}", s)),
+            GitReference::Tag(ref s) => ("tag", format!("refs/tags/{}", s)),
+            GitReference::Rev(ref s) if s.starts_with("refs/") => ("ref", s.clone()),
+            GitReference::Rev(..) => return Ok(()),
+        };
+        let config = try!(git_configuration());
+        let cfg = try!(git2::Config::open_default());
+        let url = rewrite_url(&cfg, self.url.to_string().as_slice());
+        if uses_cli(&cfg, &config, url.as_slice()) {
+            return Ok(())
+        }
+
+        let refs = try!(self.ls_remote());
+        if refs.is_empty() {
+            return Err(GitError::new(GitErrorKind::NotFound,
+                                     human(format!("repository `{}` has no \
+                                                    commits", self.url))))
+        }
+        let found = refs.iter().any(|&(ref name, _)| {
+            if config.case_insensitive_refs {
+                lowercase(name.as_slice()) == lowercase(refname.as_slice())
+            } else {
+                *name == refname
+            }
+        });
+        if found { return Ok(()) }
+        Err(GitError::new(GitErrorKind::NotFound,
+                          human(format!("{} `{}` doesn't exist in `{}`", kind,
+                                        reference_name(reference), self.url))))
+    }
+
+    /// Opens the database at `into` without touching the network, succeeding
+    /// only if `reference` can already be resolved locally.
+    fn checkout_offline(&self, into: &Path, reference: &GitReference)
+                        -> CargoResult<GitDatabase> {
+        let db = self.db_at(into);
+        match db {
+            Ok(ref db) if db.rev_for(reference).is_ok() => {}
+            _ => {
+                return Err(human(format!("can't fetch `{}` in offline mode and \
+                                          revision `{}` is not in the local \
+                                          cache", self.url,
+                                         reference_name(reference))))
+            }
+        }
+        db
     }
 
     pub fn db_at(&self, db_path: &Path) -> CargoResult<GitDatabase> {
-        let repo = try!(git2::Repository::open(db_path));
+        let repo = try!(git2::Repository::open(db_path).chain_error(|| {
+            human(format!("`{}` is not a valid git repository (the cache may \
+                           be corrupt); delete it and retry", db_path.display()))
+        }));
         Ok(GitDatabase {
             remote: self.clone(),
             path: db_path.clone(),
@@ -135,86 +1122,773 @@ impl GitRemote {
         })
     }
 
-    fn fetch_into(&self, dst: &git2::Repository) -> CargoResult<()> {
+    fn fetch_into(&self, dst: &git2::Repository,
+                  refspec: &str) -> GitResult<()> {
         // Create a local anonymous remote in the repository to fetch the url
         let url = self.url.to_string();
-        let refspec = "refs/heads/*:refs/heads/*";
-        fetch(dst, url.as_slice(), refspec)
+        fetch(dst, url.as_slice(), refspec).map(|_| ())
     }
 
-    fn clone_into(&self, dst: &Path) -> CargoResult<git2::Repository> {
+    /// Same as `fetch_into`, but for a database which was created by a shallow
+    /// clone, which libgit2 can't fetch into. The history is kept `depth`
+    /// commits deep, or all of it is fetched if `depth` is `None`.
+    fn fetch_into_shallow(&self, dst: &git2::Repository, refspec: &str,
+                          depth: Option<uint>) -> GitResult<()> {
         let url = self.url.to_string();
-        if dst.exists() {
-            try!(rmdir_recursive(dst));
-        }
-        try!(mkdir_recursive(dst, USER_DIR));
-        let repo = try!(git2::Repository::init_bare(dst));
-        try!(fetch(&repo, url.as_slice(), "refs/heads/*:refs/heads/*"));
-        Ok(repo)
+        let option = match depth {
+            Some(depth) => format!("--depth={}", depth),
+            None => "--unshallow".to_string(),
+        };
+        fetch_with_cli(dst, url.as_slice(), &[refspec], &[option.as_slice()])
+            .map_err(FromError::from_error)
     }
-}
 
-impl GitDatabase {
-    fn get_path<'a>(&'a self) -> &'a Path {
-        &self.path
+    fn clone_into(&self, dst: &Path, refspec: &str,
+                  alternates: &[Path]) -> GitResult<git2::Repository> {
+        let url = self.url.to_string();
+        clone_staged(dst, |staged| {
+            try!(mkdir_recursive(staged, USER_DIR));
+            let mut repo = try!(git2::Repository::init_bare(staged));
+            if alternates.len() > 0 {
+                try!(borrow_objects(staged, alternates));
+                // The alternates are only picked up when the repository is
+                // opened.
+                drop(repo);
+                repo = try!(git2::Repository::open(staged));
+            }
+            try!(fetch(&repo, url.as_slice(), refspec));
+            drop(repo);
+            try!(dissociate(staged));
+            Ok(())
+        })
     }
 
-    pub fn copy_to(&self, rev: GitRevision, dest: &Path)
-                   -> CargoResult<GitCheckout> {
-        let checkout = match git2::Repository::open(dest) {
-            Ok(repo) => {
-                let checkout = GitCheckout::new(dest, self, rev, repo);
-                if !checkout.is_fresh() {
-                    try!(checkout.fetch());
-                    try!(checkout.reset());
-                    assert!(checkout.is_fresh());
-                }
-                checkout
-            }
-            Err(..) => try!(GitCheckout::clone_into(dest, self, rev)),
-        };
+    /// Clones this remote into a fresh database at `dst`, but only fetches the
+    /// last `depth` commits of each branch.
+    ///
+    /// libgit2 doesn't know how to perform shallow fetches, so this shells out
+    /// to the `git` binary. Revisions which fall outside of the shallow slice
+    /// are fetched on demand by `GitDatabase::rev_for`, unless offline.
+    pub fn clone_into_shallow(&self, dst: &Path, depth: uint)
+                              -> CargoResult<GitDatabase> {
+        self.shallow_clone(dst, "refs/heads/*:refs/heads/*",
+                           format!("--depth={}", depth).as_slice())
+    }
+
+    /// Same as `clone_into_shallow`, but only fetches the commits made after
+    /// `since`, which is any date `git fetch --shallow-since` understands.
+    pub fn clone_into_shallow_since(&self, dst: &Path, since: &str)
+                                    -> CargoResult<GitDatabase> {
+        self.shallow_clone(dst, "refs/heads/*:refs/heads/*",
+                           format!("--shallow-since={}", since).as_slice())
+    }
+
+    /// Seeds the database at `into` from the `git bundle` file at `bundle`
+    /// rather than from the remote itself, for builds which can't reach the
+    /// network at all. `reference` has to be in the bundle.
+    ///
+    /// The database is still this remote's, so it can be updated from the
+    /// remote later on. libgit2 can't read bundles, so this shells out to the
+    /// `git` binary.
+    pub fn checkout_from_bundle(&self, into: &Path, bundle: &Path,
+                                reference: &GitReference)
+                                -> GitResult<GitDatabase> {
+        let lock = try!(RepositoryLock::acquire(into));
+        invalidate_rev_cache(into);
+
+        let repo = match git2::Repository::open(into) {
+            Ok(repo) => repo,
+            Err(..) => {
+                try!(mkdir_recursive(into, USER_DIR));
+                try!(git2::Repository::init_bare(into))
+            }
+        };
+        let source = bundle.display().to_string();
+        try!(fetch_with_cli(&repo, source.as_slice(), &["refs/heads/*:refs/heads/*"],
+                            &[]).chain_error(|| {
+            human(format!("failed to unbundle {} into {}", bundle.display(),
+                          into.display()))
+        }));
+
+        // Looking up a revision may deepen a shallow database, which takes the
+        // lock itself.
+        drop(lock);
+        let db = GitDatabase { remote: self.clone(), path: into.clone(), repo: repo };
+        try!(db.rev_for(reference).map_err(|e| {
+            e.context(human(format!("`{}` isn't in the bundle {}",
+                                    reference_name(reference), bundle.display())))
+        }));
+        Ok(db)
+    }
+
+    /// Clones `refspec` into a fresh database at `dst`, passing `option` to
+    /// `git fetch` to decide where the history is cut off.
+    fn shallow_clone(&self, dst: &Path, refspec: &str, option: &str)
+                     -> CargoResult<GitDatabase> {
+        let url = self.url.to_string();
+        invalidate_rev_cache(dst);
+        let repo = try!(clone_staged(dst, |staged| {
+            try!(mkdir_recursive(staged, USER_DIR));
+            let repo = try!(git2::Repository::init_bare(staged));
+            fetch_with_cli(&repo, url.as_slice(), &[refspec],
+                           &[option]).chain_error(|| {
+                internal(format!("failed to shallow clone into: {}", dst.display()))
+            })
+        }));
+        Ok(GitDatabase { remote: self.clone(), path: dst.clone(), repo: repo })
+    }
+}
+
+/// An advisory lock on a git database or checkout, released when dropped.
+///
+/// The lock is taken on a file next to the repository. The OS releases it by
+/// itself should cargo die while holding it, so it never goes stale.
+struct RepositoryLock {
+    _lock: FileLock,
+}
+
+impl RepositoryLock {
+    fn acquire(repo: &Path) -> CargoResult<RepositoryLock> {
+        let name = format!("{}.lock", repo.filename_str().unwrap_or("repo"));
+        let path = repo.dir_path().join(name);
+        try!(mkdir_recursive(&repo.dir_path(), USER_DIR));
+
+        loop {
+            let lock = try!(FileLock::open(&path).chain_error(|| {
+                internal(format!("failed to lock {}", repo.display()))
+            }));
+            let locked = try!(lock.try_lock().chain_error(|| {
+                internal(format!("failed to lock {}", repo.display()))
+            }));
+            if !locked {
+                info!("waiting for lock {}", path.display());
+                try!(lock.lock().chain_error(|| {
+                    internal(format!("failed to lock {}", repo.display()))
+                }));
+            }
+            // The previous holder removes the file as it releases the lock,
+            // in which case the lock has to be taken on the new file instead.
+            if lock.is_at() {
+                return Ok(RepositoryLock { _lock: lock })
+            }
+        }
+    }
+}
+
+impl GitDatabase {
+    fn get_path<'a>(&'a self) -> &'a Path {
+        &self.path
+    }
+
+    /// Brings the checkout of `rev` at `dest` up to date, returning it along
+    /// with what had to be done to it.
+    pub fn copy_to(&self, rev: GitRevision, dest: &Path)
+                   -> CargoResult<(GitCheckout, CheckoutUpdate)> {
+        self.copy(rev, dest, false, None)
+    }
+
+    /// Like `copy_to`, but all branches are fetched from the remote into the
+    /// database first, and an existing checkout is always fetched into and
+    /// reset, even if it looks fresh. This is for when the user explicitly
+    /// asked for a refresh, as the history may have been force-pushed.
+    ///
+    /// In offline mode only the checkout is refreshed from the database.
+    pub fn copy_to_forced(&self, rev: GitRevision, dest: &Path)
+                          -> CargoResult<(GitCheckout, CheckoutUpdate)> {
+        if !offline() {
+            try!(self.fetch_refspec("refs/heads/*:refs/heads/*"));
+        }
+        self.copy(rev, dest, true, None)
+    }
+
+    /// Like `copy_to`, but also checks that the tree of `rev` is
+    /// `expected_tree` (as recorded earlier with `tree_id`), so the contents
+    /// of the checkout are pinned down on top of the commit they're from.
+    pub fn copy_to_pinned(&self, rev: GitRevision, dest: &Path,
+                          expected_tree: &str)
+                          -> CargoResult<(GitCheckout, CheckoutUpdate)> {
+        let tree = try!(git2::Oid::from_str(expected_tree).chain_error(|| {
+            human(format!("`{}` is not a valid tree hash", expected_tree))
+        }));
+        self.copy(rev, dest, false, Some(tree))
+    }
+
+    /// Returns the id of the tree of revision `rev`, which identifies all of
+    /// its contents, for pinning them down with `copy_to_pinned`.
+    pub fn tree_id(&self, rev: &GitRevision) -> CargoResult<String> {
+        let commit = try!(self.repo.find_commit(rev.0).chain_error(|| {
+            human(format!("failed to find commit `{}`", rev))
+        }));
+        Ok(commit.tree_id().to_string())
+    }
+
+    fn copy(&self, rev: GitRevision, dest: &Path, force: bool,
+            expected_tree: Option<git2::Oid>)
+            -> CargoResult<(GitCheckout, CheckoutUpdate)> {
+        // Builds running at the same time may bring the same checkout up to
+        // date, so they take turns.
+        let _lock = try!(RepositoryLock::acquire(dest));
+        let (checkout, update) = match git2::Repository::open(dest) {
+            Ok(repo) => {
+                let checkout = GitCheckout::new(dest, self, rev, repo);
+                let update = if !force && checkout.is_fresh() {
+                    debug!("checkout {} is fresh", dest.display());
+                    CheckoutUpdate::Fresh
+                } else {
+                    // The revision is often already in the checkout (e.g. it
+                    // was checked out before), so only fetch if it's missing.
+                    let update = if !force && checkout.has_revision() {
+                        CheckoutUpdate::Reset
+                    } else {
+                        try!(checkout.fetch());
+                        if !checkout.has_revision() {
+                            return Err(FromError::from_error(revision_not_found(
+                                &checkout.revision.to_string(), &self.remote.url)))
+                        }
+                        CheckoutUpdate::Fetched
+                    };
+                    try!(checkout.reset());
+                    update
+                };
+                (checkout, update)
+            }
+            Err(..) => {
+                (try!(GitCheckout::clone_into(dest, self, rev)),
+                 CheckoutUpdate::Cloned)
+            }
+        };
+        match expected_tree {
+            Some(tree) => try!(checkout.check_tree_id(tree)),
+            None => {}
+        }
         try!(checkout.update_submodules().chain_error(|| {
             internal("failed to update submodules")
         }));
-        Ok(checkout)
+        try!(checkout.touch());
+        Ok((checkout, update))
+    }
+
+    /// Determines what `copy_to` would have to do to get a checkout of `rev` at
+    /// `dest`, without changing anything.
+    pub fn checkout_state(&self, rev: &GitRevision, dest: &Path) -> CheckoutState {
+        match git2::Repository::open(dest) {
+            Ok(repo) => GitCheckout::new(dest, self, rev.clone(), repo).state(),
+            Err(..) => CheckoutState::NeedsClone,
+        }
+    }
+
+    pub fn rev_for(&self, reference: &GitReference) -> GitResult<GitRevision> {
+        let key = (self.path.clone(), reference.clone());
+        let cached = REV_CACHE.with(|cache| {
+            cache.borrow().get(&key).map(|rev| rev.clone())
+        });
+        match cached {
+            Some(rev) => return Ok(rev),
+            None => {}
+        }
+
+        let rev = try!(match self.resolve(reference) {
+            Err(..) if self.is_empty() => {
+                Err(GitError::new(GitErrorKind::NotFound,
+                                  human(format!("repository `{}` has no commits",
+                                                self.remote.url))))
+            }
+            // A revision may just be older than the history we have locally,
+            // so deepen the database to the full history and try again. Branches
+            // and tags have their tips fetched, so they're never cut off.
+            Err(..) if is_rev(reference) && self.is_shallow() && !offline() => {
+                try!(self.unshallow());
+                self.resolve(reference)
+            }
+            ret => ret,
+        });
+        REV_CACHE.with(|cache| cache.borrow_mut().insert(key, rev.clone()));
+        Ok(rev)
+    }
+
+    /// Fetches only what's needed to resolve `reference` from the remote into
+    /// this database, and then resolves it.
+    ///
+    /// In offline mode nothing is fetched, so `reference` has to already be
+    /// in the database.
+    pub fn fetch_ref(&self, reference: &GitReference) -> GitResult<GitRevision> {
+        if !offline() {
+            try!(self.fetch_refspec(refspec(reference).as_slice()));
+        }
+        self.rev_for(reference)
+    }
+
+    /// Resolves all of `refs` at once, such as the different branches and tags
+    /// of a repository the members of a workspace depend on.
+    ///
+    /// If any of them can't be resolved with what's in the database then all
+    /// branches (and tags) are fetched, just once, before resolving them again.
+    /// Lookups go through the same cache as `rev_for`.
+    pub fn rev_for_many(&self, refs: &[GitReference]) -> GitResult<Vec<GitRevision>> {
+        let missing = refs.iter().any(|reference| self.rev_for(reference).is_err());
+        if missing && !offline() {
+            try!(self.fetch_refspec("refs/heads/*:refs/heads/*"));
+        }
+        refs.iter().map(|reference| self.rev_for(reference)).collect()
+    }
+
+    /// Fetches `refspec` from the remote into this database.
+    fn fetch_refspec(&self, refspec: &str) -> GitResult<()> {
+        let _lock = try!(RepositoryLock::acquire(&self.path));
+        invalidate_rev_cache(&self.path);
+        self.remote.fetch_into(&self.repo, refspec).map_err(|e| {
+            e.context(internal(format!("failed to fetch `{}` into {}",
+                                       self.remote.url, self.path.display())))
+        })
+    }
+
+    /// Fetches the commit of `reference` directly if it's a full commit hash
+    /// which isn't reachable from any of the branches and tags fetched so far,
+    /// such as a commit which a branch was force-pushed away from.
+    ///
+    /// Servers only hand out such commits if they're configured to, through
+    /// `uploadpack.allowReachableSHA1InWant` or `uploadpack.allowAnySHA1InWant`.
+    /// libgit2 can't ask for them at all, so the `git` binary does it.
+    fn fetch_unadvertised(&self, reference: &GitReference) -> GitResult<()> {
+        let rev = match *reference {
+            GitReference::Rev(ref s) => match from_str::<GitRevision>(s.as_slice()) {
+                Some(rev) => rev,
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        if self.rev_for(reference).is_ok() { return Ok(()) }
+
+        invalidate_rev_cache(&self.path);
+        let url = self.remote.url.to_string();
+        let refspec = format!("{0}:refs/cargo/revs/{0}", rev);
+        match fetch_with_cli(&self.repo, url.as_slice(), &[refspec.as_slice()], &[]) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                info!("failed to fetch {} from {} directly: {}", rev, url,
+                      e.description());
+                Err(revision_not_found(rev.to_string().as_slice(), &self.remote.url))
+            }
+        }
+    }
+
+    /// Lists the branches and tags in this database along with the revisions
+    /// they point at, sorted by name.
+    pub fn references(&self) -> CargoResult<Vec<(String, GitRevision)>> {
+        let mut refs = Vec::new();
+        for reference in try!(self.repo.references()) {
+            match (reference.name(), reference.target()) {
+                (Some(name), Some(id)) if name.starts_with("refs/heads/") ||
+                                          name.starts_with("refs/tags/") => {
+                    refs.push((name.to_string(), GitRevision(id)));
+                }
+                _ => {}
+            }
+        }
+        refs.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(refs)
+    }
+
+    /// Returns the tip of `reference` if it's a branch which has moved on from
+    /// `locked`, the revision it resolved to when it was locked.
+    ///
+    /// Only the database itself is consulted, so this is only as up to date as
+    /// the last fetch into it.
+    pub fn moved_branch(&self, reference: &GitReference,
+                        locked: &GitRevision) -> Option<GitRevision> {
+        match *reference {
+            GitReference::Branch(..) => {}
+            GitReference::Tag(..) | GitReference::Rev(..) => return None,
+        }
+        match self.rev_for(reference) {
+            Ok(ref tip) if tip != locked => Some(tip.clone()),
+            _ => None,
+        }
     }
 
-    pub fn rev_for(&self, reference: &GitReference) -> CargoResult<GitRevision> {
+    fn resolve(&self, reference: &GitReference) -> GitResult<GitRevision> {
         let id = match *reference {
             GitReference::Tag(ref s) => {
-                try!((|| {
-                    let refname = format!("refs/tags/{}", s);
+                let refname = try!(self.ref_name("refs/tags/", s.as_slice()));
+                let tag = refname.as_slice().slice_from("refs/tags/".len());
+                let id = try!((|| {
                     let id = try!(self.repo.refname_to_id(refname.as_slice()));
-                    let tag = try!(self.repo.find_tag(id));
-                    let obj = try!(tag.peel());
-                    Ok(obj.id())
+                    // Annotated tags are tag objects which need peeling, but
+                    // lightweight tags point straight at the commit.
+                    match self.repo.find_tag(id) {
+                        Ok(tag) => Ok(try!(tag.peel()).id()),
+                        Err(..) => Ok(try!(self.repo.find_commit(id)).id()),
+                    }
                 }).chain_error(|| {
                     human(format!("failed to find tag `{}`", s))
-                }))
+                }).map_err(|e| GitError::new(GitErrorKind::NotFound, e)));
+                let config = try!(git_configuration());
+                if config.signing_keys.len() > 0 {
+                    try!(self.verify_tag(tag, config.signing_keys.as_slice()));
+                }
+                id
             }
             GitReference::Branch(ref s) => {
+                let refname = try!(self.ref_name("refs/heads/", s.as_slice()));
+                let branch = refname.as_slice().slice_from("refs/heads/".len());
                 try!((|| {
-                    let b = try!(self.repo.find_branch(s.as_slice(),
-                                                       git2::BranchType::Local));
-                    b.get().target().require(|| {
-                        human(format!("branch `{}` did not have a target", s))
-                    })
+                    // Databases keep branches under `refs/heads`, but fall back
+                    // to remote-tracking branches so that resolution doesn't
+                    // hinge on the layout of the refspecs fetched into it.
+                    let remote = format!("origin/{}", branch);
+                    let b = self.repo.find_branch(branch, git2::BranchType::Local)
+                                .or_else(|_| {
+                        self.repo.find_branch(remote.as_slice(),
+                                              git2::BranchType::Remote)
+                    });
+                    match b {
+                        Ok(b) => b.get().target().require(|| {
+                            human(format!("branch `{}` did not have a target", s))
+                        }),
+                        Err(..) => {
+                            Ok(try!(self.repo.refname_to_id(refname.as_slice())))
+                        }
+                    }
                 }).chain_error(|| {
                     human(format!("failed to find branch `{}`", s))
-                }))
+                }).map_err(|e| GitError::new(GitErrorKind::NotFound, e)))
             }
             GitReference::Rev(ref s) => {
-                let obj = try!(self.repo.revparse_single(s.as_slice()));
-                obj.id()
+                try!(self.check_tag_branch_clash(s.as_slice()).map_err(|e| {
+                    GitError::new(GitErrorKind::Ambiguous, e)
+                }));
+                let obj = match self.repo.revparse_single(s.as_slice()) {
+                    Ok(obj) => obj,
+                    Err(ref e) if e.code() == git2::ErrorCode::Ambiguous => {
+                        return Err(GitError::new(GitErrorKind::Ambiguous,
+                                                 self.ambiguous_rev(s.as_slice())))
+                    }
+                    Err(e) => return Err(FromError::from_error(e)),
+                };
+                // Revisions such as annotated tags resolve to other objects
+                // which have to be peeled to the commit they point at.
+                match obj.kind() {
+                    Some(git2::ObjectType::Commit) => obj.id(),
+                    _ => {
+                        let commit = format!("{}^{{commit}}", s);
+                        match self.repo.revparse_single(commit.as_slice()) {
+                            Ok(commit) => commit.id(),
+                            Err(..) => {
+                                return Err(GitError::new(GitErrorKind::NotFound,
+                                    human(format!("revision `{}` doesn't refer \
+                                                   to a commit", s))))
+                            }
+                        }
+                    }
+                }
             }
         };
         Ok(GitRevision(id))
     }
 
+    /// Returns the full name the reference `name` under `prefix` (such as
+    /// `refs/tags/`) goes by in this database.
+    ///
+    /// That's just the name as given unless it doesn't exist and
+    /// `git.case-insensitive-refs` is set, in which case a reference which
+    /// only differs in case is used instead.
+    fn ref_name(&self, prefix: &str, name: &str) -> GitResult<String> {
+        let exact = format!("{}{}", prefix, name);
+        if self.repo.refname_to_id(exact.as_slice()).is_ok() ||
+           !try!(git_configuration()).case_insensitive_refs {
+            return Ok(exact)
+        }
+
+        let wanted = lowercase(exact.as_slice());
+        let mut matches = Vec::new();
+        for reference in try!(self.repo.references()) {
+            match reference.name() {
+                Some(refname) if refname.starts_with(prefix) &&
+                                 lowercase(refname) == wanted => {
+                    matches.push(refname.to_string());
+                }
+                _ => {}
+            }
+        }
+
+        matches.sort();
+        match matches.len() {
+            // Let the caller complain about the reference not existing.
+            0 => Ok(exact),
+            1 => {
+                let found = matches.pop().unwrap();
+                warn(format!("`{}` doesn't exist in {}, using `{}` which only \
+                              differs in case", exact, self.remote.url, found));
+                Ok(found)
+            }
+            _ => {
+                Err(GitError::new(GitErrorKind::Ambiguous, human(format!(
+                    "`{}` doesn't exist in {}, and more than one reference \
+                     matches it when ignoring case: {}", exact,
+                    self.remote.url, matches.connect(", ")))))
+            }
+        }
+    }
+
+    /// Verifies that the tag `tag` carries a good signature made by one of the
+    /// GPG keys in `keys`.
+    ///
+    /// libgit2 can't check signatures, so this is delegated to
+    /// `git verify-tag`, which in turn requires `gpg` to be installed.
+    fn verify_tag(&self, tag: &str, keys: &[String]) -> CargoResult<()> {
+        let output = try!(try!(process("git")).arg("verify-tag").arg("--raw")
+                                             .arg(tag)
+                                             .cwd(self.path.clone())
+                                             .exec_with_output()
+                                             .chain_error(|| {
+            human(format!("the signature of tag `{}` could not be verified", tag))
+        }));
+
+        // The status lines of gpg are printed on stderr, and every key which
+        // made a good signature shows up in a `VALIDSIG` line.
+        let status = String::from_utf8_lossy(output.error.as_slice());
+        let trusted = status.as_slice().lines().filter_map(|line| {
+            let mut words = line.words();
+            match (words.next(), words.next(), words.next()) {
+                (Some("[GNUPG:]"), Some("VALIDSIG"), Some(fingerprint)) => {
+                    Some(fingerprint)
+                }
+                _ => None,
+            }
+        }).any(|fingerprint| {
+            keys.iter().any(|key| fingerprint.ends_with(key.as_slice()))
+        });
+
+        if trusted {
+            Ok(())
+        } else {
+            Err(human(format!("tag `{}` is not signed by any of the keys in \
+                               `git.allowed-signing-keys`", tag)))
+        }
+    }
+
+    /// Fails if `rev` names both a tag and a branch which point at different
+    /// commits, as it's unclear which of the two was meant.
+    fn check_tag_branch_clash(&self, rev: &str) -> CargoResult<()> {
+        let tag = self.repo.revparse_single(format!("refs/tags/{}^{{}}",
+                                                    rev).as_slice());
+        let branch = self.repo.revparse_single(format!("refs/heads/{}",
+                                                       rev).as_slice());
+        match (tag, branch) {
+            (Ok(ref tag), Ok(ref branch)) if tag.id() != branch.id() => {
+                Err(human(format!("revision `{0}` is ambiguous, it names both \
+                                   the tag `{0}` ({1}) and the branch `{0}` \
+                                   ({2})\nuse `tag = \"{0}\"` or \
+                                   `branch = \"{0}\"` in the manifest instead",
+                                  rev, tag.id(), branch.id())))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Creates an error for a `rev` which is an abbreviation of more than one
+    /// commit, listing all of the commits it could refer to.
+    fn ambiguous_rev(&self, rev: &str) -> Box<CargoError> {
+        let mut candidates = Vec::new();
+        let _ = (|| -> CargoResult<()> {
+            let mut walk = try!(self.repo.revwalk());
+            try!(walk.push_glob("refs/*"));
+            for id in walk {
+                let id = id.to_string();
+                if id.as_slice().starts_with(rev) && !candidates.contains(&id) {
+                    candidates.push(id);
+                }
+            }
+            Ok(())
+        })();
+        candidates.sort();
+
+        let mut msg = format!("revision `{}` is ambiguous", rev);
+        if candidates.len() > 0 {
+            msg.push_str(", it could refer to any of:");
+            for id in candidates.iter() {
+                msg.push_str(format!("\n    {}", id).as_slice());
+            }
+        }
+        msg.push_str("\nuse the full 40-character hash of the commit instead");
+        human(msg)
+    }
+
     pub fn has_ref<S: Str>(&self, reference: S) -> CargoResult<()> {
         try!(self.repo.revparse_single(reference.as_slice()));
         Ok(())
     }
+
+    /// Lists the revisions which are checked out under `root`, the directory
+    /// containing all git checkouts, along with the path of each checkout.
+    pub fn list_checkouts(&self, root: &Path)
+                          -> CargoResult<Vec<(GitRevision, Path)>> {
+        let dir = checkouts_dir(root, &self.remote.url);
+        if !dir.exists() {
+            return Ok(Vec::new())
+        }
+        let entries = try!(fs::readdir(&dir).chain_error(|| {
+            internal(format!("failed to read checkouts in {}", dir.display()))
+        }));
+        Ok(entries.into_iter().filter(|path| path.is_dir()).filter_map(|path| {
+            let oid = path.filename_str().and_then(|name| {
+                git2::Oid::from_str(name).ok()
+            });
+            match oid {
+                Some(oid) => Some((GitRevision(oid), path)),
+                None => None,
+            }
+        }).collect())
+    }
+
+    /// Deletes all checkouts under `root` except for those of the revisions in
+    /// `keep`. The database itself is never touched.
+    ///
+    /// Each checkout goes along with its `cargo-last-used` marker (which lives
+    /// in its `.git`) and the lock file next to it, which is removed as the
+    /// lock taken to delete the checkout is released.
+    pub fn prune(&self, root: &Path, keep: &[GitRevision]) -> CargoResult<()> {
+        for (rev, path) in try!(self.list_checkouts(root)).into_iter() {
+            if keep.contains(&rev) {
+                continue
+            }
+            // Don't pull the checkout out from under a build using it
+            let _lock = try!(RepositoryLock::acquire(&path));
+            info!("pruning checkout {}", path.display());
+            try!(remove_dir_all(&path).chain_error(|| {
+                human(format!("failed to remove checkout {}", path.display()))
+            }));
+        }
+        Ok(())
+    }
+
+    /// Returns when the checkout at `path` was last handed out by `copy_to`, in
+    /// milliseconds since the epoch, or `None` if it never was (such as
+    /// checkouts made by older versions of cargo).
+    pub fn last_used(&self, path: &Path) -> Option<u64> {
+        last_used_marker(path).stat().ok().map(|stat| stat.modified)
+    }
+
+    /// Returns the shortest abbreviation of `rev`, of at least 7 characters,
+    /// which doesn't also refer to another object of this database.
+    pub fn short_rev(&self, rev: &GitRevision) -> String {
+        let full = rev.to_string();
+        let mut len = SHORT_REV_LEN;
+        while len < full.len() {
+            let short = full.as_slice().slice_to(len);
+            match self.repo.revparse_single(short) {
+                Err(ref e) if e.code() == git2::ErrorCode::Ambiguous => len += 1,
+                _ => break,
+            }
+        }
+        full.as_slice().slice_to(len).to_string()
+    }
+
+    /// Returns whether the commit `rev` is present in this database.
+    pub fn contains(&self, rev: &GitRevision) -> bool {
+        self.repo.find_object(rev.0, None).is_ok()
+    }
+
+    /// Returns the time at which `rev` was committed, in seconds since the
+    /// epoch.
+    pub fn commit_time(&self, rev: GitRevision) -> CargoResult<i64> {
+        let commit = try!(self.repo.find_commit(rev.0).chain_error(|| {
+            human(format!("failed to find commit `{}`", rev))
+        }));
+        Ok(commit.committer().when().seconds())
+    }
+
+    /// Reads the file at `path` (relative to the root of the repository) as of
+    /// `rev` straight out of the database, such as to peek at a manifest
+    /// without going through a whole checkout.
+    pub fn read_file(&self, rev: &GitRevision, path: &Path) -> CargoResult<Vec<u8>> {
+        let missing = || {
+            human(format!("`{}` doesn't exist at revision {} of `{}`",
+                          path.display(), rev.short(), self.remote.url))
+        };
+        let commit = try!(self.repo.find_commit(rev.0).chain_error(|| {
+            human(format!("failed to find commit `{}`", rev))
+        }));
+        let mut tree = try!(commit.tree());
+        let components = path.str_components().collect::<Vec<Option<&str>>>();
+        for (i, component) in components.iter().enumerate() {
+            let component = try!(component.require(|| internal("non-utf8 path")));
+            let last = i + 1 == components.len();
+            let entry = tree.iter().find(|entry| entry.name() == Some(component))
+                            .map(|entry| (entry.id(), entry.kind()));
+            match entry {
+                Some((id, Some(git2::ObjectType::Tree))) if !last => {
+                    tree = try!(self.repo.find_tree(id));
+                }
+                Some((id, Some(git2::ObjectType::Blob))) if last => {
+                    let blob = try!(self.repo.find_blob(id));
+                    return Ok(blob.content().to_vec())
+                }
+                _ => return Err(missing()),
+            }
+        }
+        Err(missing())
+    }
+
+    /// Lists the files which differ between revisions `from` and `to` along
+    /// with how they changed, sorted by path. Submodules count as modified when
+    /// the commit they're at changed.
+    pub fn diff(&self, from: GitRevision, to: GitRevision)
+                -> CargoResult<Vec<(ChangeKind, Path)>> {
+        let old = try!(self.files_at(&from));
+        let new = try!(self.files_at(&to));
+        let mut changes = Vec::new();
+        for (path, id) in old.iter() {
+            match new.get(path) {
+                Some(new_id) if new_id == id => {}
+                Some(..) => changes.push((path.clone(), ChangeKind::Modified)),
+                None => changes.push((path.clone(), ChangeKind::Deleted)),
+            }
+        }
+        for path in new.keys() {
+            if !old.contains_key(path) {
+                changes.push((path.clone(), ChangeKind::Added));
+            }
+        }
+        changes.sort();
+        Ok(changes.into_iter().map(|(path, change)| {
+            (change, Path::new(path))
+        }).collect())
+    }
+
+    fn files_at(&self, rev: &GitRevision) -> CargoResult<HashMap<String, git2::Oid>> {
+        let commit = try!(self.repo.find_commit(rev.0).chain_error(|| {
+            human(format!("failed to find commit `{}`", rev))
+        }));
+        let mut files = HashMap::new();
+        try!(tree_files(&self.repo, &try!(commit.tree()), &Path::new(""),
+                        &mut files));
+        Ok(files)
+    }
+
+    /// Returns whether nothing at all was fetched into this database, as the
+    /// remote doesn't have a single commit.
+    fn is_empty(&self) -> bool {
+        self.repo.is_empty().unwrap_or(false)
+    }
+
+    /// Returns whether this database was created by a shallow clone and is
+    /// missing some of the remote's history.
+    pub fn is_shallow(&self) -> bool {
+        self.path.join("shallow").exists()
+    }
+
+    fn unshallow(&self) -> CargoResult<()> {
+        info!("unshallow {}", self.path.display());
+        let _lock = try!(RepositoryLock::acquire(&self.path));
+        invalidate_rev_cache(&self.path);
+        let url = self.remote.url.to_string();
+        fetch_with_cli(&self.repo, url.as_slice(), &["refs/heads/*:refs/heads/*"],
+                       &["--unshallow"]).chain_error(|| {
+            internal(format!("failed to deepen shallow clone {}",
+                             self.path.display()))
+        })
+    }
 }
 
 impl<'a> GitCheckout<'a> {
@@ -230,177 +1904,3016 @@ impl<'a> GitCheckout<'a> {
         }
     }
 
+    /// Returns a handle on this checkout which can be sent to other threads to
+    /// read its files concurrently.
+    pub fn handle(&self) -> GitCheckoutHandle {
+        GitCheckoutHandle {
+            location: self.location.clone(),
+            revision: self.revision.clone(),
+        }
+    }
+
+    /// Opens the existing checkout at `path` without fetching into or resetting
+    /// it, for just reading its files. It's an error if the checkout isn't at
+    /// `revision`.
+    pub fn open_existing(path: &Path, database: &'a GitDatabase,
+                         revision: GitRevision) -> CargoResult<GitCheckout<'a>> {
+        let repo = try!(git2::Repository::open(path).chain_error(|| {
+            human(format!("no checkout of `{}` at {}", database.remote.url,
+                          path.display()))
+        }));
+        let checkout = GitCheckout::new(path, database, revision, repo);
+        match checkout.state() {
+            CheckoutState::Fresh => Ok(checkout),
+            _ => {
+                let head = checkout.repo.revparse_single("HEAD")
+                                   .map(|h| h.id().to_string());
+                Err(human(format!("the checkout at {} should be at revision \
+                                   `{}`, but HEAD is at `{}`", path.display(),
+                                  checkout.revision,
+                                  head.unwrap_or("<unknown>".to_string()))))
+            }
+        }
+    }
+
     fn clone_into(into: &Path, database: &'a GitDatabase,
                   revision: GitRevision)
                   -> CargoResult<GitCheckout<'a>>
     {
-        let repo = try!(GitCheckout::clone_repo(database.get_path(), into));
+        let repo = try!(GitCheckout::clone_repo(database, into));
+
+        // libgit2 guesses these settings by probing the filesystem when the
+        // repository is created, which doesn't always get it right, so spell
+        // out what we want before the first reset.
+        {
+            let mut cfg = try!(repo.config());
+            try!(cfg.set_bool("core.filemode", cfg!(unix)));
+            try!(cfg.set_bool("core.symlinks", cfg!(unix)));
+        }
+
         let checkout = GitCheckout::new(into, database, revision, repo);
+        if !checkout.has_revision() {
+            return Err(FromError::from_error(revision_not_found(
+                &checkout.revision.to_string(), &database.remote.url)))
+        }
         try!(checkout.reset());
         Ok(checkout)
     }
 
-    fn clone_repo(source: &Path, into: &Path) -> CargoResult<git2::Repository> {
+    fn clone_repo(database: &GitDatabase, into: &Path)
+                  -> CargoResult<git2::Repository> {
+        let source = database.get_path();
         let dirname = into.dir_path();
 
         try!(mkdir_recursive(&dirname, USER_DIR).chain_error(|| {
             human(format!("Couldn't mkdir {}", dirname.display()))
         }));
 
-        if into.exists() {
-            try!(rmdir_recursive(into).chain_error(|| {
-                human(format!("Couldn't rmdir {}", into.display()))
+        let url = try!(GitCheckout::database_url(database));
+
+        clone_staged(into, |staged| {
+            // libgit2 can't clone out of a shallow repository as it expects the
+            // entire history to be present, so let git take care of it instead.
+            if database.is_shallow() {
+                try!(try!(process("git")).arg("clone").arg("--quiet")
+                                         .arg(url.as_slice()).arg(staged)
+                                         .exec_with_output().chain_error(|| {
+                    internal(format!("failed to clone `{}` (from its local \
+                                      database at {}) into {}",
+                                     database.remote.url, source.display(),
+                                     into.display()))
+                }));
+                return Ok(())
+            }
+
+            match GitCheckout::share_objects(database, staged) {
+                Ok(..) => return Ok(()),
+                Err(e) => {
+                    info!("failed to share the objects of {}, cloning instead: \
+                           {}", source.display(), e.description());
+                    if staged.exists() {
+                        try!(remove_dir_all(staged));
+                    }
+                }
+            }
+
+            try!(git2::Repository::clone(url.as_slice(), staged).chain_error(|| {
+                internal(format!("failed to clone `{}` (from its local database \
+                                  at {}) into {}", database.remote.url,
+                                 source.display(), into.display()))
             }));
-        }
+            Ok(())
+        })
+    }
 
-        let url = try!(source.to_url().map_err(human));
-        let url = url.to_string();
-        let repo = try!(git2::Repository::clone(url.as_slice(),
-                                                into).chain_error(|| {
-            internal(format!("failed to clone {} into {}", source.display(),
-                             into.display()))
-        }));
-        Ok(repo)
+    /// Creates an empty repository at `into` which borrows all of its objects
+    /// from `database` through `objects/info/alternates`, so checking out a
+    /// revision doesn't need a copy of the whole history.
+    ///
+    /// This is what `git worktree` would give us as well, but libgit2 doesn't
+    /// know how to open linked worktrees.
+    fn share_objects(database: &GitDatabase, into: &Path)
+                     -> CargoResult<git2::Repository> {
+        let repo = try!(git2::Repository::init(into));
+        let info = repo.path().join("objects").join("info");
+        try!(mkdir_recursive(&info, USER_DIR));
+        let objects = database.path.join("objects");
+        try!(File::create(&info.join("alternates"))
+                  .write_str(format!("{}\n", objects.display()).as_slice()));
+        drop(repo);
+        // The alternates are only picked up when the repository is opened.
+        Ok(try!(git2::Repository::open(into)))
     }
 
-    fn is_fresh(&self) -> bool {
-        match self.repo.revparse_single("HEAD") {
-            Ok(head) => head.id().to_string() == self.revision.to_string(),
-            _ => false,
+    /// Returns the `file://` url checkouts of `database` are cloned from, after
+    /// making sure that it really leads to the database: an absolute path to a
+    /// bare repository, which comes back the same from the url.
+    fn database_url(database: &GitDatabase) -> CargoResult<String> {
+        let source = database.get_path();
+        let invalid = |why: &str| {
+            human(format!("can't check out `{}` from its local database at {}: \
+                           {}", database.remote.url, source.display(), why))
+        };
+        if !source.is_absolute() {
+            return Err(invalid("the path isn't absolute"))
         }
+        match git2::Repository::open(source) {
+            Ok(ref repo) if repo.is_bare() => {}
+            Ok(..) => return Err(invalid("it isn't a bare repository")),
+            Err(..) => return Err(invalid("it isn't a git repository")),
+        }
+        let url = try!(source.to_url().map_err(human));
+        match url.to_file_path() {
+            Ok(ref path) if path == source => {}
+            _ => return Err(invalid(format!("the path doesn't convert to a url \
+                                             faithfully (`{}`)", url).as_slice())),
+        }
+        Ok(url.to_string())
     }
 
-    fn fetch(&self) -> CargoResult<()> {
-        info!("fetch {}", self.repo.path().display());
-        let url = try!(self.database.path.to_url().map_err(human));
-        let url = url.to_string();
-        let refspec = "refs/heads/*:refs/heads/*";
-        try!(fetch(&self.repo, url.as_slice(), refspec));
-        Ok(())
+    /// Compares the checkout's `HEAD` with the revision it should be at.
+    pub fn state(&self) -> CheckoutState {
+        match self.repo.revparse_single("HEAD") {
+            Ok(ref head) if head.id() == self.revision.0 => CheckoutState::Fresh,
+            Ok(head) => {
+                CheckoutState::NeedsReset(Some(GitRevision(head.id())),
+                                          self.revision.clone())
+            }
+            Err(..) => CheckoutState::NeedsReset(None, self.revision.clone()),
+        }
     }
 
-    fn reset(&self) -> CargoResult<()> {
-        info!("reset {} to {}", self.repo.path().display(), self.revision);
-        let object = try!(self.repo.find_object(self.revision.0, None));
-        try!(self.repo.reset(&object, git2::ResetType::Hard, None, None));
-        Ok(())
+    fn is_fresh(&self) -> bool {
+        self.state() == CheckoutState::Fresh
     }
 
-    fn update_submodules(&self) -> CargoResult<()> {
-        return update_submodules(&self.repo);
-
-        fn update_submodules(repo: &git2::Repository) -> CargoResult<()> {
-            info!("update submodules for: {}", repo.path().display());
+    /// Fails if the files in the working directory don't make up the tree
+    /// `expected`.
+    ///
+    /// The tree is worked out by adding each file the index tracks to it again
+    /// from the working directory, so files which were changed after they were
+    /// checked out count too. Submodules count as the commit the index has
+    /// them at, and so do files left out of a sparse checkout.
+    fn check_tree_id(&self, expected: git2::Oid) -> CargoResult<()> {
+        let config = try!(git_configuration());
+        let url = self.database.remote.url.to_string();
+        let sparse = config.sparse_paths(url.as_slice());
 
-            for mut child in try!(repo.submodules()).into_iter() {
-                try!(child.init(false));
-                let url = try!(child.url().require(|| {
-                    internal("non-utf8 url for submodule")
-                }));
+        let mut index = try!(self.repo.index());
+        let paths = range(0, index.len()).filter_map(|i| index.get(i)).filter(|entry| {
+            entry.mode != 0o160000
+        }).map(|entry| {
+            String::from_utf8_lossy(entry.path.as_bytes_no_nul()).into_owned()
+        }).filter(|path| {
+            sparse.len() == 0 || in_sparse_paths(path.as_slice(), sparse)
+        }).collect::<Vec<String>>();
+        for path in paths.iter() {
+            try!(index.add_path(&Path::new(path.as_slice())).chain_error(|| {
+                human(format!("failed to read `{}` in the checkout at {}", path,
+                              self.location.display()))
+            }));
+        }
+        let actual = try!(index.write_tree());
+        if actual == expected { return Ok(()) }
+        Err(human(format!("the contents of the checkout of revision `{}` of `{}` \
+                           don't match what they were pinned to: their tree is \
+                           `{}`, but `{}` was expected", self.revision,
+                          self.database.remote.url, actual, expected)))
+    }
 
-                // A submodule which is listed in .gitmodules but not actually
-                // checked out will not have a head id, so we should ignore it.
-                let head = match child.head_id() {
-                    Some(head) => head,
-                    None => continue,
-                };
+    /// Records that the checkout was just used, for `GitDatabase::last_used`.
+    fn touch(&self) -> CargoResult<()> {
+        let marker = last_used_marker(&self.location);
+        File::create(&marker).map(|_| ()).chain_error(|| {
+            internal(format!("failed to write {}", marker.display()))
+        })
+    }
 
-                // If the submodule hasn't been checked out yet, we need to
-                // clone it. If it has been checked out and the head is the same
-                // as the submodule's head, then we can bail out and go to the
-                // next submodule.
-                let head_and_repo = child.open().and_then(|repo| {
-                    Ok((try!(repo.head()).target(), repo))
-                });
-                let repo = match head_and_repo {
-                    Ok((head, repo)) => {
-                        if child.head_id() == head {
-                            continue
-                        }
-                        repo
-                    }
-                    Err(..) => {
-                        let path = repo.path().dir_path().join(child.path());
-                        try!(git2::Repository::clone(url, &path))
-                    }
-                };
+    fn has_revision(&self) -> bool {
+        self.repo.find_object(self.revision.0, None).is_ok()
+    }
 
-                // Fetch data from origin and reset to the head commit
-                let refspec = "refs/heads/*:refs/heads/*";
-                try!(fetch(&repo, url, refspec).chain_error(|| {
-                    internal(format!("failed to fetch submodule `{}` from {}",
-                                     child.name().unwrap_or(""), url))
-                }));
+    /// Lists the files whose changes in the working directory (or the index)
+    /// would be lost by a hard reset. Untracked files are left alone by resets
+    /// so they aren't included.
+    fn local_changes(&self) -> Vec<String> {
+        let statuses = match self.repo.statuses(None) {
+            Ok(statuses) => statuses,
+            Err(..) => return Vec::new(),
+        };
+        let lost = git2::STATUS_INDEX_NEW | git2::STATUS_INDEX_MODIFIED |
+                   git2::STATUS_INDEX_DELETED | git2::STATUS_INDEX_TYPECHANGE |
+                   git2::STATUS_WT_MODIFIED | git2::STATUS_WT_DELETED |
+                   git2::STATUS_WT_TYPECHANGE;
+        statuses.iter().filter(|entry| entry.status().intersects(lost))
+                .filter_map(|entry| entry.path().map(|p| p.to_string()))
+                .collect()
+    }
 
-                let obj = try!(repo.find_object(head, None));
-                try!(repo.reset(&obj, git2::ResetType::Hard, None, None));
-                try!(update_submodules(&repo));
-            }
-            Ok(())
+    /// Checks that every file in the tree of `self.revision` is present in the
+    /// working directory with exactly the contents recorded in the repository,
+    /// to catch checkouts which were corrupted or tampered with.
+    ///
+    /// Files which aren't part of the revision and submodules aren't checked.
+    /// Nothing is written, neither to the working directory nor the index.
+    pub fn verify(&self) -> CargoResult<()> {
+        let commit = try!(self.repo.find_commit(self.revision.0));
+        let tree = try!(commit.tree());
+        let mut mismatches = Vec::new();
+        try!(verify_tree(&self.repo, &tree, &self.location, &Path::new(""),
+                         &mut mismatches));
+        if mismatches.len() == 0 {
+            return Ok(())
+        }
+        let mut msg = format!("checkout of `{}` at {} doesn't match revision {}",
+                              self.database.remote.url, self.location.display(),
+                              self.revision);
+        for mismatch in mismatches.iter() {
+            msg.push_str(format!("\n    {}", mismatch).as_slice());
         }
+        Err(human(msg))
     }
-}
 
-fn with_authentication<T>(url: &str,
-                          cfg: &git2::Config,
-                          f: |git2::Credentials| -> CargoResult<T>)
-                          -> CargoResult<T> {
-    // Prepare the authentication callbacks.
-    //
+    fn fetch(&self) -> CargoResult<()> {
+        info!("fetch {}", self.repo.path().display());
+        let url = try!(GitCheckout::database_url(self.database));
+        let refspec = "refs/heads/*:refs/heads/*";
+        let ret = if self.database.is_shallow() {
+            fetch_with_cli(&self.repo, url.as_slice(), &[refspec], &[])
+        } else {
+            fetch(&self.repo, url.as_slice(), refspec).map(|_| ())
+                                                      .map_err(FromError::from_error)
+        };
+
+        // We fetch from the local database, but its path alone would be rather
+        // confusing in an error, so mention where the database came from.
+        ret.chain_error(|| {
+            internal(format!("failed to fetch `{}` (through its local database \
+                              at {}) into {}", self.database.remote.url,
+                             self.database.path.display(),
+                             self.location.display()))
+        })
+    }
+
+    /// Writes the files of the checked out revision to `dst` as a plain
+    /// directory without any of git's metadata, for vendoring.
+    ///
+    /// Executable bits and symlinks are kept the same way as by `reset`, and
+    /// the trees of submodules (which have to be checked out already) are
+    /// written along with the rest.
+    pub fn export(&self, dst: &Path) -> CargoResult<()> {
+        let commit = try!(self.repo.find_commit(self.revision.0));
+        let tree = try!(commit.tree());
+        try!(mkdir_recursive(dst, USER_DIR));
+        export_tree(&self.repo, &tree, &self.location, dst).chain_error(|| {
+            human(format!("failed to export revision {} of `{}` to {}",
+                          self.revision.short(), self.database.remote.url,
+                          dst.display()))
+        })
+    }
+
+    /// Hard resets the working tree to `self.revision`.
+    ///
+    /// Executable bits and symlinks recorded in the tree are reproduced on the
+    /// filesystem. On platforms without them (Windows) executable bits are
+    /// ignored and each symlink is checked out as a plain file containing the
+    /// path it points to, like git does.
+    fn reset(&self) -> CargoResult<()> {
+        info!("reset {} to {}", self.repo.path().display(), self.revision);
+
+        let config = try!(git_configuration());
+        let url = self.database.remote.url.to_string();
+        let sparse = config.sparse_paths(url.as_slice());
+
+        // Checkouts aren't meant to be edited, but if something (like a build
+        // script) did then at least say which changes are thrown away. Files
+        // left out of a sparse checkout would all show up as deleted, so only
+        // the paths it contains are looked at.
+        let changes = self.local_changes().into_iter().filter(|path| {
+            sparse.len() == 0 || in_sparse_paths(path.as_slice(), sparse)
+        }).collect::<Vec<String>>();
+        if changes.len() > 0 {
+            warn(format!("discarding changes to the checkout of `{}` at {}:\n    \
+                          {}", url, self.location.display(),
+                         changes.connect("\n    ")));
+        }
+
+        if sparse.len() > 0 {
+            try!(self.sparse_reset(sparse));
+        } else {
+            try!(self.clear_sparse());
+            let object = try!(self.repo.find_object(self.revision.0, None));
+            match self.repo.reset(&object, git2::ResetType::Hard, None, None) {
+                Ok(()) => {}
+                Err(e) => return Err(try!(self.explain_reset_failure(e))),
+            }
+        }
+        if config.clean_untracked {
+            try!(self.remove_untracked());
+        }
+
+        // Make sure the reset actually took effect, so a corrupt database or
+        // an interfering hook doesn't cause the wrong sources to be built.
+        if !self.is_fresh() {
+            let head = self.repo.revparse_single("HEAD").map(|h| h.id().to_string());
+            return Err(human(format!("failed to check out revision `{}` into \
+                                      `{}`, HEAD is at `{}` instead",
+                                     self.revision, self.location.display(),
+                                     head.unwrap_or("<unknown>".to_string()))))
+        }
+        self.fetch_lfs_files()
+    }
+
+    /// Deletes the files and directories in the working directory which git
+    /// doesn't track, like `git clean -fd` does. Ignored files are kept.
+    fn remove_untracked(&self) -> CargoResult<()> {
+        let statuses = try!(self.repo.statuses(None));
+        let untracked = statuses.iter().filter(|entry| {
+            entry.status().contains(git2::STATUS_WT_NEW)
+        }).filter_map(|entry| entry.path().map(|p| p.to_string()))
+          .collect::<Vec<String>>();
+        for path in untracked.iter() {
+            info!("removing untracked {} from {}", path, self.location.display());
+            let file = self.location.join(path.as_slice());
+            let ret = if file.is_dir() {
+                rmdir_recursive(&file)
+            } else {
+                fs::unlink(&file)
+            };
+            try!(ret.chain_error(|| {
+                internal(format!("failed to remove {}", file.display()))
+            }));
+        }
+        Ok(())
+    }
+
+    /// Explains why a reset failed with `err` if it's because the tree has
+    /// paths which only differ in case, which collide on case-insensitive
+    /// filesystems (the default on OS X and Windows).
+    fn explain_reset_failure(&self, err: git2::Error) -> CargoResult<Box<CargoError>> {
+        let commit = try!(self.repo.find_commit(self.revision.0));
+        let mut paths = Vec::new();
+        try!(tree_paths(&self.repo, &try!(commit.tree()), &Path::new(""),
+                        &mut paths));
+
+        let mut seen = HashMap::new();
+        let mut collisions = Vec::new();
+        for path in paths.into_iter() {
+            let key = lowercase(path.as_slice());
+            match seen.get(&key) {
+                Some(other) => {
+                    collisions.push(format!("{} and {}", other, path));
+                    continue
+                }
+                None => {}
+            }
+            seen.insert(key, path);
+        }
+        if collisions.is_empty() {
+            return Ok(FromError::from_error(err))
+        }
+        Ok(human(format!("failed to check out revision `{}` of `{}`, as it has \
+                          paths which only differ in case and this filesystem \
+                          can't tell them apart:\n    {}", self.revision,
+                         self.database.remote.url,
+                         collisions.connect("\n    "))).concrete().with_cause(err))
+    }
+
+    /// Checks out the revision with only the directories in `paths` (and the
+    /// manifest at the root) in the working directory.
+    ///
+    /// libgit2 doesn't know about sparse checkouts, so this configures one and
+    /// leaves the checking out to the `git` binary.
+    fn sparse_reset(&self, paths: &[String]) -> CargoResult<()> {
+        {
+            let mut cfg = try!(self.repo.config());
+            try!(cfg.set_bool("core.sparseCheckout", true));
+        }
+        let mut patterns = "/Cargo.toml\n".to_string();
+        for path in paths.iter() {
+            let path = path.as_slice().trim_chars('/');
+            patterns.push_str(format!("/{}/\n", path).as_slice());
+        }
+        let info = self.repo.path().join("info");
+        try!(mkdir_recursive(&info, USER_DIR));
+        try!(File::create(&info.join("sparse-checkout"))
+                  .write_str(patterns.as_slice()));
+
+        try!(try!(process("git")).arg("checkout").arg("--quiet").arg("--force")
+                                 .arg(self.revision.to_string())
+                                 .cwd(self.location.clone())
+                                 .exec_with_output().chain_error(|| {
+            human(format!("failed to check out `{}` sparsely into {}",
+                          self.database.remote.url, self.location.display()))
+        }));
+        Ok(())
+    }
+
+    /// Turns the checkout back into a full one if it was made sparse before
+    /// `git.sparse-paths` stopped applying to it, so git doesn't keep leaving
+    /// out files which libgit2 checks out.
+    fn clear_sparse(&self) -> CargoResult<()> {
+        let mut cfg = try!(self.repo.config());
+        if cfg.get_bool("core.sparseCheckout").unwrap_or(false) {
+            try!(cfg.set_bool("core.sparseCheckout", false));
+        }
+        let patterns = self.repo.path().join("info").join("sparse-checkout");
+        if patterns.exists() {
+            try!(fs::unlink(&patterns));
+        }
+        Ok(())
+    }
+
+    /// Replaces the git-lfs pointers checked out in place of files stored with
+    /// git-lfs with the real files, by running `git lfs pull`.
+    ///
+    /// Building with the pointers would fail in confusing ways, so it's an
+    /// error if this doesn't work out.
+    fn fetch_lfs_files(&self) -> CargoResult<()> {
+        let commit = try!(self.repo.find_commit(self.revision.0));
+        let tree = try!(commit.tree());
+        // Looking for pointers means reading every file, so don't bother
+        // unless the repository puts some files through git-lfs at all.
+        if !try!(uses_lfs(&self.repo, &tree)) {
+            return Ok(())
+        }
+        let mut pointers = Vec::new();
+        try!(lfs_pointers(&self.repo, &tree, &Path::new(""), &mut pointers));
+        if pointers.len() == 0 {
+            return Ok(())
+        }
+
+        // The checkout's origin is the local database, so let git-lfs work out
+        // where the files live from the url of the actual remote instead, as
+        // it would for a plain clone.
+        let url = self.database.remote.url.to_string();
+        let origin = format!("remote.origin.url={}", url);
+        let ret = try!(process("git")).arg("-c").arg(origin.as_slice())
+                                      .arg("lfs").arg("pull")
+                                      .cwd(self.location.clone())
+                                      .exec_with_output();
+        ret.map(|_| ()).chain_error(|| {
+            let files = pointers.iter().map(|p| p.display().to_string())
+                                .collect::<Vec<String>>();
+            human(format!("`{}` stores some files with git-lfs, but they \
+                           couldn't be downloaded:\n    {}\n\
+                           is git-lfs installed?", url,
+                          files.connect("\n    ")))
+        })
+    }
+
+    /// Updates all submodules of the checkout, recursively.
+    ///
+    /// The submodules of the checkout itself are updated in parallel, with up
+    /// to `git.submodule-jobs` at a time, while anything nested inside of them
+    /// is updated serially.
+    ///
+    /// Submodules can be left out with `git.submodules` and
+    /// `git.skip-submodules`, in which case a warning lists them.
+    fn update_submodules(&self) -> CargoResult<()> {
+        let url = self.database.remote.url.to_string();
+        let config = try!(git_configuration());
+        let patterns = config.skip_submodules.iter().map(|p| {
+            Pattern::new(p.as_slice())
+        }).collect::<Vec<Pattern>>();
+
+        let mut updates = Vec::new();
+        let mut skipped = Vec::new();
+        for update in try!(submodule_updates(&self.repo, self.revision.0,
+                                             &[url.clone()])).into_iter() {
+            let path = update.path.path_relative_from(&self.location)
+                                  .unwrap_or(update.path.clone());
+            if !config.submodules || patterns.iter().any(|p| p.matches_path(&path)) {
+                skipped.push(path.display().to_string());
+            } else {
+                updates.push(update);
+            }
+        }
+        if skipped.len() > 0 {
+            warn(format!("not checking out these submodules of `{}`, as \
+                          configured:\n    {}", url, skipped.connect("\n    ")));
+        }
+        if updates.len() == 0 {
+            return Ok(())
+        }
+
+        let pool = TaskPool::new(config.submodule_jobs);
+        let (tx, rx) = channel();
+        let amt = updates.len();
+        for update in updates.into_iter() {
+            let tx = tx.clone();
+            let location = self.location.clone();
+            let url = url.clone();
+            // Repositories can't be shared across threads, so each job opens
+            // the checkout for itself.
+            pool.execute(move|| {
+                let cfg = git2::Repository::open(&location).and_then(|parent| {
+                    parent.config()
+                });
+                let ret = match cfg {
+                    Ok(cfg) => update_submodule(&cfg, &update, &mut vec![url]),
+                    Err(e) => Err(FromError::from_error(e)),
+                };
+                tx.send((update.name, ret, take_warnings()));
+            });
+        }
+        // Only the jobs hold on to senders now, so should one of them panic
+        // the results stop coming rather than being waited for forever.
+        drop(tx);
+
+        let mut ret = Ok(());
+        let mut finished = 0u;
+        for (name, result, warnings) in rx.iter().take(amt) {
+            finished += 1;
+            for warning in warnings.into_iter() {
+                warn(warning);
+            }
+            if ret.is_ok() {
+                ret = result.chain_error(|| {
+                    human(format!("failed to update submodule `{}`", name))
+                });
+            }
+        }
+        if ret.is_ok() && finished < amt {
+            ret = Err(internal(format!("{} of the submodules of `{}` failed to \
+                                        update", amt - finished, url)));
+        }
+        ret
+    }
+}
+
+/// A submodule which needs to be checked out at `head`.
+struct SubmoduleUpdate {
+    name: String,
+    // The path of the submodule's working directory.
+    path: Path,
+    url: String,
+    branch: Option<String>,
+    head: git2::Oid,
+}
+
+/// Finds the submodules of `parent` which may need updating, now that it's
+/// checked out at `rev`.
+///
+/// `parents` is the stack of urls of the repositories enclosing `parent`,
+/// used to bail out on submodules which (indirectly) contain themselves.
+fn submodule_updates(parent: &git2::Repository, rev: git2::Oid,
+                     parents: &[String]) -> CargoResult<Vec<SubmoduleUpdate>> {
+    info!("update submodules for: {}", parent.path().display());
+
+    let tree = try!(try!(parent.find_commit(rev)).tree());
+    let mut updates = Vec::new();
+    for mut child in try!(parent.submodules()).into_iter() {
+        try!(child.init(false));
+        let url = try!(child.url().require(|| {
+            internal("non-utf8 url for submodule")
+        }));
+        // Relative urls are relative to the url of the parent repository
+        let url = resolve_submodule_url(parents.last().unwrap().as_slice(), url);
+
+        if parents.iter().any(|p| *p == url) {
+            let mut cycle = parents.to_vec();
+            cycle.push(url);
+            return Err(human(format!("submodule `{}` forms a cycle: {}",
+                                     child.path().display(),
+                                     cycle.connect(" -> "))))
+        }
+        if parents.len() > MAX_SUBMODULE_DEPTH {
+            return Err(human(format!("submodule `{}` is nested more than {} \
+                                      levels deep: {}",
+                                     child.path().display(),
+                                     MAX_SUBMODULE_DEPTH,
+                                     parents.connect(" -> "))))
+        }
+
+        // Submodules are checked out at exactly the commit recorded in the
+        // tree of `rev`, whatever their branches are up to. A submodule which
+        // is listed in .gitmodules but not actually in the tree is ignored.
+        let head = match try!(gitlink(parent, &tree, child.path())) {
+            Some(head) => head,
+            None => continue,
+        };
+
+        let name = child.name().unwrap_or("").to_string();
+        updates.push(SubmoduleUpdate {
+            branch: submodule_branch(parent, name.as_slice()),
+            name: name,
+            path: parent.path().dir_path().join(child.path()),
+            url: url,
+            head: head,
+        });
+    }
+    Ok(updates)
+}
+
+/// Returns the commit recorded for the submodule at `path` in `tree`, if there
+/// is a submodule there.
+fn gitlink(repo: &git2::Repository, tree: &git2::Tree,
+           path: &Path) -> CargoResult<Option<git2::Oid>> {
+    let mut components = path.str_components().peekable();
+    let mut tree = try!(repo.find_tree(tree.id()));
+    loop {
+        let name = match components.next() {
+            Some(Some(name)) => name,
+            Some(None) | None => return Ok(None),
+        };
+        let (id, kind) = match tree.get_name(name) {
+            Some(entry) => (entry.id(), entry.kind()),
+            None => return Ok(None),
+        };
+        match (components.is_empty(), kind) {
+            (true, Some(git2::ObjectType::Commit)) => return Ok(Some(id)),
+            (false, Some(git2::ObjectType::Tree)) => {
+                tree = try!(repo.find_tree(id));
+            }
+            _ => return Ok(None),
+        }
+    }
+}
+
+/// Checks out the submodule `update` of the repository whose configuration is
+/// `parent_cfg`, followed by all of its own submodules.
+///
+/// Configuration is only read once for each repository in the tree of
+/// submodules, and passed down to the fetches of its submodules.
+fn update_submodule(parent_cfg: &git2::Config, update: &SubmoduleUpdate,
+                    parents: &mut Vec<String>) -> CargoResult<()> {
+    let url = update.url.as_slice();
+    let head = update.head;
+
+    // If the submodule hasn't been checked out yet, we need to clone it. If it
+    // has been checked out and the head is the same as the submodule's head,
+    // then we can bail out and go to the next submodule.
+    let head_and_repo = git2::Repository::open(&update.path).and_then(|repo| {
+        Ok((try!(repo.head()).target(), repo))
+    });
+    let repo = match head_and_repo {
+        Ok((current, repo)) => {
+            if current == Some(head) {
+                return Ok(())
+            }
+            repo
+        }
+        // Rather than cloning (which can't authenticate) start out with an
+        // empty repository, the fetch below fills it in.
+        Err(..) => try!(git2::Repository::init(&update.path)),
+    };
+
+    // Fetch data from origin and reset to the head commit. Only fetch as much
+    // as is needed to get a hold of the commit: nothing if it's already
+    // present, then the branch the submodule tracks (or, without one, the
+    // commit itself and then the remote's HEAD), and only then all of the
+    // branches in case the commit isn't the tip of any of those.
+    let mut refspecs = Vec::new();
+    match update.branch {
+        Some(ref b) => refspecs.push(format!("refs/heads/{0}:refs/heads/{0}", b)),
+        None => {
+            refspecs.push(head.to_string());
+            refspecs.push("+HEAD:refs/remotes/origin/HEAD".to_string());
+        }
+    }
+    refspecs.push("refs/heads/*:refs/heads/*".to_string());
+    let mut error = None;
+    for refspec in refspecs.iter() {
+        if repo.find_object(head, None).is_ok() { break }
+        // Not every server hands out commits by their id, so a failed fetch
+        // only matters if none of the later ones turn up the commit either.
+        match fetch_with_parent(&repo, Some(parent_cfg), url,
+                                refspec.as_slice(), None) {
+            Ok(..) => {}
+            Err(e) => {
+                debug!("fetching {} of submodule `{}` failed: {}", refspec,
+                       update.name, e);
+                error = Some(e);
+            }
+        }
+    }
+    if repo.find_object(head, None).is_err() {
+        if let Some(e) = error {
+            return Err(e).chain_error(|| {
+                internal(format!("failed to fetch submodule `{}` from {}",
+                                 update.name, url))
+            })
+        }
+    }
+
+    let obj = try!(repo.find_object(head, None));
+    try!(repo.reset(&obj, git2::ResetType::Hard, None, None));
+
+    parents.push(url.to_string());
+    let updates = try!(submodule_updates(&repo, head, parents.as_slice()));
+    if updates.len() > 0 {
+        let cfg = try!(repo.config());
+        for update in updates.iter() {
+            try!(update_submodule(&cfg, update, parents));
+        }
+    }
+    parents.pop();
+    Ok(())
+}
+
+/// Compares the files of `tree`, which are at `prefix` in the tree of the
+/// revision, with those in `dir`, adding a description of each one which
+/// differs to `mismatches`.
+fn verify_tree(repo: &git2::Repository, tree: &git2::Tree, dir: &Path,
+               prefix: &Path, mismatches: &mut Vec<String>) -> CargoResult<()> {
+    for entry in tree.iter() {
+        let name = try!(entry.name().require(|| {
+            internal("non-utf8 file name in tree")
+        }));
+        let path = dir.join(name);
+        let relative = prefix.join(name);
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                let subtree = try!(repo.find_tree(entry.id()));
+                try!(verify_tree(repo, &subtree, &path, &relative, mismatches));
+            }
+            Some(git2::ObjectType::Blob) => {
+                let blob = try!(repo.find_blob(entry.id()));
+                // Symlinks are stored as blobs of the path they point to, and
+                // are only checked out as such on unix.
+                let contents = if entry.filemode() == 0o120000 && cfg!(unix) {
+                    fs::readlink(&path).map(|p| p.into_vec())
+                } else {
+                    File::open(&path).read_to_end()
+                };
+                match contents {
+                    Ok(ref contents) if contents.as_slice() == blob.content() => {}
+                    Ok(..) => {
+                        mismatches.push(format!("modified: {}", relative.display()))
+                    }
+                    Err(..) => {
+                        mismatches.push(format!("missing: {}", relative.display()))
+                    }
+                }
+            }
+            // Submodules have their own checkouts.
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Writes the files of `tree` to `dst`. The repositories of submodules are
+/// expected in the same place under `workdir` as the submodule is in the tree.
+fn export_tree(repo: &git2::Repository, tree: &git2::Tree, workdir: &Path,
+               dst: &Path) -> CargoResult<()> {
+    for entry in tree.iter() {
+        let name = try!(entry.name().require(|| {
+            internal("non-utf8 file name in tree")
+        }));
+        let workdir = workdir.join(name);
+        let path = dst.join(name);
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                let subtree = try!(repo.find_tree(entry.id()));
+                try!(clear_path(&path, true));
+                try!(mkdir_recursive(&path, USER_DIR));
+                try!(export_tree(repo, &subtree, &workdir, &path));
+            }
+            Some(git2::ObjectType::Blob) => {
+                let blob = try!(repo.find_blob(entry.id()));
+                // Writing through a symlink which was left behind would write
+                // wherever it points to instead.
+                try!(clear_path(&path, false));
+                if entry.filemode() == 0o120000 && cfg!(unix) {
+                    try!(fs::symlink(&Path::new(blob.content()), &path));
+                    continue
+                }
+                try!(File::create(&path).write(blob.content()));
+                if entry.filemode() == 0o100755 && cfg!(unix) {
+                    try!(fs::chmod(&path, io::FilePermission::from_bits_truncate(0o755)));
+                }
+            }
+            // A submodule shows up as the commit it's at, which is in its own
+            // repository.
+            Some(git2::ObjectType::Commit) => {
+                let sub = try!(git2::Repository::open(&workdir).chain_error(|| {
+                    human(format!("submodule at {} isn't checked out",
+                                  workdir.display()))
+                }));
+                let commit = try!(sub.find_commit(entry.id()));
+                try!(clear_path(&path, true));
+                try!(mkdir_recursive(&path, USER_DIR));
+                try!(export_tree(&sub, &try!(commit.tree()), &workdir, &path));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Removes whatever is at `path` without following symlinks, except for a
+/// directory if `keep_dirs` is set.
+fn clear_path(path: &Path, keep_dirs: bool) -> CargoResult<()> {
+    match fs::lstat(path) {
+        Err(..) => Ok(()),
+        Ok(ref stat) if stat.kind == io::FileType::Directory => {
+            if keep_dirs { Ok(()) } else { remove_dir_all(path) }
+        }
+        Ok(..) => fs::unlink(path).chain_error(|| {
+            internal(format!("failed to remove {}", path.display()))
+        }),
+    }
+}
+
+/// Collects the ids of the blobs of all files in `tree`, which is at `prefix` in
+/// the tree of a revision, by their paths. Submodules aren't descended into but
+/// are collected along with the commit they're at.
+fn tree_files(repo: &git2::Repository, tree: &git2::Tree, prefix: &Path,
+              files: &mut HashMap<String, git2::Oid>) -> CargoResult<()> {
+    for entry in tree.iter() {
+        let name = try!(entry.name().require(|| {
+            internal("non-utf8 file name in tree")
+        }));
+        let path = prefix.join(name);
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                let subtree = try!(repo.find_tree(entry.id()));
+                try!(tree_files(repo, &subtree, &path, files));
+            }
+            _ => {
+                files.insert(path.display().to_string(), entry.id());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Collects the paths of all files and directories in `tree`, which is at
+/// `prefix` in the tree of a revision. Submodules aren't descended into.
+fn tree_paths(repo: &git2::Repository, tree: &git2::Tree, prefix: &Path,
+              paths: &mut Vec<String>) -> CargoResult<()> {
+    for entry in tree.iter() {
+        let name = try!(entry.name().require(|| {
+            internal("non-utf8 file name in tree")
+        }));
+        let path = prefix.join(name);
+        paths.push(path.display().to_string());
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                let subtree = try!(repo.find_tree(entry.id()));
+                try!(tree_paths(repo, &subtree, &path, paths));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `path` (relative to the root of a checkout) is part of a
+/// sparse checkout of `paths`, which always includes the top-level manifest.
+fn in_sparse_paths(path: &str, paths: &[String]) -> bool {
+    path == "Cargo.toml" || paths.iter().any(|dir| {
+        let dir = dir.as_slice().trim_chars('/');
+        path == dir || (path.starts_with(dir) &&
+                        path.slice_from(dir.len()).starts_with("/"))
+    })
+}
+
+/// Returns whether any `.gitattributes` file in `tree` hands files to the
+/// git-lfs filter.
+fn uses_lfs(repo: &git2::Repository, tree: &git2::Tree) -> CargoResult<bool> {
+    for entry in tree.iter() {
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                let subtree = try!(repo.find_tree(entry.id()));
+                if try!(uses_lfs(repo, &subtree)) {
+                    return Ok(true)
+                }
+            }
+            Some(git2::ObjectType::Blob)
+                if entry.name() == Some(".gitattributes") => {
+                let blob = try!(repo.find_blob(entry.id()));
+                let attributes = String::from_utf8_lossy(blob.content());
+                if attributes.as_slice().contains("filter=lfs") {
+                    return Ok(true)
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// Finds the blobs of `tree` (which is at `prefix` in the tree of a revision)
+/// which are git-lfs pointers rather than actual files.
+fn lfs_pointers(repo: &git2::Repository, tree: &git2::Tree, prefix: &Path,
+                pointers: &mut Vec<Path>) -> CargoResult<()> {
+    for entry in tree.iter() {
+        let name = try!(entry.name().require(|| {
+            internal("non-utf8 file name in tree")
+        }));
+        let path = prefix.join(name);
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                let subtree = try!(repo.find_tree(entry.id()));
+                try!(lfs_pointers(repo, &subtree, &path, pointers));
+            }
+            Some(git2::ObjectType::Blob) => {
+                // Pointers are tiny, so there's no need to look at anything
+                // which is much bigger.
+                let blob = try!(repo.find_blob(entry.id()));
+                let header = b"version https://git-lfs.github.com/spec/v1";
+                if blob.content().len() < 1024 &&
+                   blob.content().starts_with(header) {
+                    pointers.push(path);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the url of a submodule against the url of its parent repository,
+/// `base`, like `git submodule` does.
+///
+/// Urls which aren't relative (starting with `./` or `../`) are left alone.
+fn resolve_submodule_url(base: &str, url: &str) -> String {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return url.to_string()
+    }
+
+    let mut base = base.trim_right_matches('/').to_string();
+    let mut sep = '/';
+    let mut rel = url;
+    loop {
+        if rel.starts_with("./") {
+            rel = rel.slice_from(2);
+        } else if rel.starts_with("../") {
+            rel = rel.slice_from(3);
+            // scp-like urls (`git@host:path`) have a `:` before the path
+            match base.as_slice().rfind(|c: char| c == '/' || c == ':') {
+                Some(i) => {
+                    sep = base.as_slice().char_at(i);
+                    base.truncate(i);
+                }
+                None => {}
+            }
+        } else {
+            break
+        }
+    }
+    format!("{}{}{}", base, sep, rel)
+}
+
+/// Returns the branch which the submodule `name` of `repo` is configured to
+/// track in `.gitmodules`, if any.
+fn submodule_branch(repo: &git2::Repository, name: &str) -> Option<String> {
+    let path = repo.path().dir_path().join(".gitmodules");
+    let cfg = match git2::Config::open(&path) {
+        Ok(cfg) => cfg,
+        Err(..) => return None,
+    };
+    let key = format!("submodule.{}.branch", name);
+    cfg.get_str(key.as_slice()).ok().map(|s| s.to_string())
+}
+
+/// Recursively removes the directory `path`.
+///
+/// git marks some of its files (such as pack files) read-only, which makes
+/// removing them fail on Windows, so if the first attempt fails all files are
+/// made writable before trying once more.
+fn remove_dir_all(path: &Path) -> CargoResult<()> {
+    match rmdir_recursive(path) {
+        Ok(()) => return Ok(()),
+        Err(e) => info!("failed to remove {}, retrying: {}", path.display(), e),
+    }
+    for file in try!(fs::walk_dir(path)) {
+        let _ = fs::chmod(&file, io::USER_RWX);
+    }
+    try!(rmdir_recursive(path));
+    Ok(())
+}
+
+/// Forgets all cached reference resolutions for the database at `path`.
+fn invalidate_rev_cache(path: &Path) {
+    REV_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let stale = cache.keys().filter(|&&(ref p, _)| p == path)
+                         .map(|k| k.clone()).collect::<Vec<_>>();
+        for key in stale.iter() {
+            cache.remove(key);
+        }
+    })
+}
+
+/// Recreates the `config` of the repository whose git directory is `git_dir`
+/// if it's missing or can't be read (say, after a disk failure), as nothing
+/// works without it. Cargo doesn't keep anything in there for its databases
+/// and checkouts which can't be set up again, so a default one does.
+fn repair_config(git_dir: &Path, bare: bool) -> CargoResult<()> {
+    let path = git_dir.join("config");
+    let readable = git2::Config::open(&path).ok().map_or(false, |cfg| {
+        cfg.get_i32("core.repositoryformatversion").is_ok()
+    });
+    if path.exists() && readable {
+        return Ok(())
+    }
+
+    warn(format!("the git configuration at {} is missing or damaged, \
+                  recreating it", path.display()));
+    try!(File::create(&path).write_str(format!("\
+[core]
+\trepositoryformatversion = 0
+\tfilemode = {}
+\tbare = {}
+", cfg!(unix), bare).as_slice()).chain_error(|| {
+        internal(format!("failed to recreate {}", path.display()))
+    }));
+    Ok(())
+}
+
+/// Returns whether every reference of `repo` points at an object which is
+/// actually present, which isn't the case when writing the objects of a fetch
+/// was cut short but the references were already updated.
+fn is_intact(repo: &git2::Repository) -> bool {
+    let refs = match repo.references() {
+        Ok(refs) => refs,
+        Err(..) => return false,
+    };
+    for reference in refs {
+        match reference.target() {
+            Some(id) if repo.find_object(id, None).is_err() => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+/// Returns whether writing to the file at `probe` fails because the disk or
+/// the user's quota is full.
+///
+/// libgit2 and `git` only pass on the OS errors they run into as messages (in
+/// whichever language the system speaks), so rather than picking those apart
+/// this writes a little itself and looks at the error code it gets.
+fn is_disk_full(probe: &Path) -> bool {
+    let ret = File::create(probe).and_then(|mut file| {
+        try!(file.write([0u8, ..4096].as_slice()));
+        file.fsync()
+    });
+    ret.is_err() && DISK_FULL_ERRORS.contains(&(os::errno() as uint))
+}
+
+// ENOSPC and EDQUOT
+#[cfg(target_os = "linux")]
+static DISK_FULL_ERRORS: &'static [uint] = &[28, 122];
+#[cfg(all(unix, not(target_os = "linux")))]
+static DISK_FULL_ERRORS: &'static [uint] = &[28, 69];
+// ERROR_DISK_FULL and ERROR_HANDLE_DISK_FULL
+#[cfg(windows)]
+static DISK_FULL_ERRORS: &'static [uint] = &[112, 39];
+
+/// Makes the bare repository at `dst` borrow the objects of each of the
+/// repositories at `alternates`, which are skipped if they can't be opened or
+/// have no objects to borrow.
+///
+/// The branches and tags of each alternate are also recorded under
+/// `refs/alternates/<n>/` as fetches only tell the remote about objects which
+/// are reachable from a local reference, and would otherwise download
+/// everything again.
+fn borrow_objects(dst: &Path, alternates: &[Path]) -> CargoResult<()> {
+    let mut lines = String::new();
+    for (i, alternate) in alternates.iter().enumerate() {
+        let repo = match git2::Repository::open(alternate) {
+            Ok(repo) => repo,
+            Err(e) => {
+                warn(format!("ignoring alternate repository at {}: {}",
+                             alternate.display(), e.message()));
+                continue
+            }
+        };
+        if !repo.path().join("objects").is_dir() {
+            warn(format!("ignoring alternate repository at {}: it has no \
+                          objects directory", alternate.display()));
+            continue
+        }
+        lines.push_str(format!("{}\n",
+                               repo.path().join("objects").display()).as_slice());
+        for reference in try!(repo.references()) {
+            let (name, id) = match (reference.name(), reference.target()) {
+                (Some(name), Some(id)) if name.starts_with("refs/heads/") ||
+                                          name.starts_with("refs/tags/") => {
+                    (name.slice_from("refs/".len()).to_string(), id)
+                }
+                _ => continue,
+            };
+            let path = dst.join(format!("refs/alternates/{}/{}", i, name));
+            try!(mkdir_recursive(&path.dir_path(), USER_DIR));
+            try!(File::create(&path).write_str(format!("{}\n", id).as_slice()));
+        }
+    }
+    let info = dst.join("objects").join("info");
+    try!(mkdir_recursive(&info, USER_DIR));
+    try!(File::create(&info.join("alternates")).write_str(lines.as_slice()));
+    Ok(())
+}
+
+/// Copies the objects which the bare repository at `path` borrows from its
+/// alternates (if it has any) and still needs into the repository itself, and
+/// then stops borrowing, like `git clone --dissociate` does.
+///
+/// The `refs/alternates/` recorded by `borrow_objects` are removed first, so
+/// that only what's reachable from the repository's own references is copied
+/// rather than everything the alternates have. libgit2 can't repack, so this
+/// shells out to the `git` binary.
+fn dissociate(path: &Path) -> CargoResult<()> {
+    let alternates = path.join("objects/info/alternates");
+    if !alternates.exists() {
+        return Ok(())
+    }
+    let refs = path.join("refs/alternates");
+    if refs.exists() {
+        try!(rmdir_recursive(&refs));
+    }
+    try!(try!(process("git")).arg("repack").arg("-a").arg("-d").arg("-q")
+                             .cwd(path.clone()).exec_with_output().chain_error(|| {
+        internal(format!("failed to copy the objects borrowed from alternates \
+                          into {}", path.display()))
+    }));
+    try!(fs::unlink(&alternates));
+    Ok(())
+}
+
+/// The file whose modification time records when the checkout at `path` was
+/// last used. It lives in `.git` so that it isn't part of the checkout itself.
+fn last_used_marker(path: &Path) -> Path {
+    path.join(".git").join("cargo-last-used")
+}
+
+/// Creates a repository at `dst` by running `create` on a staging directory,
+/// which is only moved to `dst` once it's complete. Whatever was at `dst` is
+/// only removed then, so a clone which fails or is interrupted never destroys
+/// a repository which was fine before.
+///
+/// Staging directories are created in `git.staging-dir` if it's set, and next
+/// to `dst` otherwise. They have to be on the same filesystem as `dst` for it
+/// to be moved into place.
+fn clone_staged<E: CargoError + FromError<Box<CargoError>>>(
+        dst: &Path, create: |&Path| -> Result<(), E>)
+        -> Result<git2::Repository, E> {
+    let staging = match staging_dir(dst) {
+        Ok(staging) => staging,
+        Err(e) => return Err(FromError::from_error(e)),
+    };
+    let staged = staging.path().join("repo");
+    match create(&staged) {
+        Ok(()) => {}
+        // Running out of disk space tends to show up as a confusing failure of
+        // the transfer, so it's spelled out. The disk has to be checked before
+        // the staging directory (and whatever filled it) is deleted.
+        Err(e) => {
+            if !is_disk_full(&staging.path().join("space-check")) {
+                return Err(e)
+            }
+            let msg = human(format!("not enough disk space to clone into {}",
+                                    dst.display()));
+            return Err(FromError::from_error(msg.concrete().with_cause(e)))
+        }
+    }
+    move_into_place(staging.path(), &staged, dst).map_err(FromError::from_error)
+}
+
+/// Creates a fresh staging directory for a repository which will end up at
+/// `dst`. It's deleted again when dropped.
+fn staging_dir(dst: &Path) -> CargoResult<TempDir> {
+    let root = match try!(git_configuration()).staging_dir {
+        Some(dir) => dir,
+        None => dst.dir_path(),
+    };
+    try!(mkdir_recursive(&root, USER_DIR));
+    TempDir::new_in(&root, "cargo-staging").chain_error(|| {
+        internal(format!("failed to create a staging directory in {}",
+                         root.display()))
+    })
+}
+
+/// Moves the repository at `staged` to `dst`. Anything which was at `dst`
+/// before is moved into the `staging` directory instead, to be deleted, and
+/// is put back should `staged` fail to move.
+fn move_into_place(staging: &Path, staged: &Path,
+                   dst: &Path) -> CargoResult<git2::Repository> {
+    let old = staging.join("old");
+    if dst.exists() {
+        try!(fs::rename(dst, &old).chain_error(|| {
+            internal(format!("failed to move {} out of the way", dst.display()))
+        }));
+    }
+    match fs::rename(staged, dst) {
+        Ok(()) => {}
+        Err(e) => {
+            if old.exists() {
+                match fs::rename(&old, dst) {
+                    Ok(()) => {}
+                    Err(e) => info!("failed to move {} back to {}: {}",
+                                    old.display(), dst.display(), e),
+                }
+            }
+            return Err(e).chain_error(|| {
+                internal(format!("failed to move {} into place at {}",
+                                 staged.display(), dst.display()))
+            })
+        }
+    }
+    if old.exists() {
+        match remove_dir_all(&old) {
+            Ok(()) => {}
+            Err(e) => info!("failed to remove {}: {}", old.display(), e),
+        }
+    }
+    Ok(try!(git2::Repository::open(dst)))
+}
+
+/// The error for a revision (such as one in a lock file) which still isn't in
+/// the repository at `url` once everything has been fetched from it.
+pub fn revision_not_found(rev: &str, url: &Url) -> GitError {
+    GitError::new(GitErrorKind::NotFound, human(format!(
+        "revision `{}` not found in `{}` even after fetching; it may have been \
+         force-pushed away or garbage collected, try `cargo update`", rev, url)))
+}
+
+/// Lowercases `s`, for comparing reference names while ignoring case.
+fn lowercase(s: &str) -> String {
+    s.chars().map(|c| c.to_lowercase()).collect()
+}
+
+/// Finds the branch which `HEAD` points at in the `listing` printed by
+/// `git ls-remote --symref`, where it shows up as `ref: refs/heads/<branch>`
+/// followed by a tab and `HEAD`.
+fn head_branch(listing: &str) -> Option<String> {
+    listing.lines().filter_map(|line| {
+        let mut parts = line.splitn(1, '\t');
+        match (parts.next(), parts.next()) {
+            (Some(target), Some("HEAD")) => Some(target),
+            _ => None,
+        }
+    }).filter_map(|target| {
+        if target.starts_with("ref: refs/heads/") {
+            Some(target.slice_from("ref: refs/heads/".len()).to_string())
+        } else {
+            None
+        }
+    }).next()
+}
+
+/// Queues up `msg` to be shown to the user as a warning.
+fn warn(msg: String) {
+    WARNINGS.with(|warnings| warnings.borrow_mut().push(msg));
+}
+
+/// Takes the warnings about git operations on this thread which haven't been
+/// shown yet, for the caller to show them.
+pub fn take_warnings() -> Vec<String> {
+    WARNINGS.with(|warnings| mem::replace(&mut *warnings.borrow_mut(), Vec::new()))
+}
+
+/// Has the progress of fetches on this thread printed on stderr through a shell
+/// configured like `config`, such as the one cargo prints its own messages on,
+/// or not printed at all if it's `None` (the default).
+pub fn show_progress(config: Option<ShellConfig>) {
+    PROGRESS.with(|progress| progress.set(config));
+}
+
+/// Returns whether network access has been disabled through the
+/// `CARGO_NET_OFFLINE` environment variable.
+pub fn offline() -> bool {
+    match os::getenv("CARGO_NET_OFFLINE") {
+        Some(s) => s.as_slice() == "1" || s.as_slice() == "true",
+        None => false,
+    }
+}
+
+/// Returns the name of the branch, tag, or revision that `reference` names.
+fn reference_name(reference: &GitReference) -> &str {
+    match *reference {
+        GitReference::Branch(ref s) |
+        GitReference::Tag(ref s) |
+        GitReference::Rev(ref s) => s.as_slice(),
+    }
+}
+
+/// Returns what the tag named by `reference` currently points at in `repo`, if
+/// it's a tag which exists there.
+fn tag_target(repo: &git2::Repository,
+              reference: &GitReference) -> Option<GitRevision> {
+    match *reference {
+        GitReference::Tag(ref s) => {
+            let refname = format!("refs/tags/{}", s);
+            repo.refname_to_id(refname.as_slice()).ok().map(GitRevision)
+        }
+        GitReference::Branch(..) | GitReference::Rev(..) => None,
+    }
+}
+
+/// Returns whether `reference` names a revision rather than a branch or tag.
+fn is_rev(reference: &GitReference) -> bool {
+    match *reference {
+        GitReference::Rev(..) => true,
+        GitReference::Branch(..) | GitReference::Tag(..) => false,
+    }
+}
+
+/// Returns the refspec needed to fetch `reference` from a remote.
+///
+/// A `GitReference::Rev` which is a full refname, such as the `refs/pull/N/head`
+/// of a pull request, is fetched as is. Those are often force-pushed, so they
+/// are fetched with a forced refspec.
+fn refspec(reference: &GitReference) -> String {
+    match *reference {
+        GitReference::Branch(ref s) => format!("refs/heads/{0}:refs/heads/{0}", s),
+        GitReference::Tag(ref s) => format!("+refs/tags/{0}:refs/tags/{0}", s),
+        GitReference::Rev(ref s) if s.starts_with("refs/") => format!("+{0}:{0}", s),
+        GitReference::Rev(..) => "refs/heads/*:refs/heads/*".to_string(),
+    }
+}
+
+/// Runs `f` with a credentials callback suitable for fetching `url`.
+///
+/// Credential helpers and usernames are looked up in each of `cfgs` in turn,
+/// so a submodule can be fetched with its own configuration first and that of
+/// its parent repository second.
+fn with_authentication<T>(url: &str,
+                          cfgs: &[&git2::Config],
+                          provider: &mut CredentialProvider,
+                          f: |git2::Credentials| -> GitResult<T>)
+                          -> GitResult<T> {
+    // Prepare the authentication callbacks.
+    //
     // We check the `allowed` types of credentials, and we try to do as much as
     // possible based on that:
     //
+    // * Whatever `provider` hands out is tried first of all, but only once.
+    //
     // * Prioritize SSH keys from the local ssh agent as they're likely the most
     //   reliable. The username here is prioritized from the credential
     //   callback, then from whatever is configured in git itself, and finally
-    //   we fall back to the generic user of `git`.
+    //   we fall back to the generic user of `git`. If the agent's keys are
+    //   rejected (or there's no agent at all) we fall back to keys on disk:
+    //   the one configured with `git.ssh-key`, followed by the standard
+    //   locations in `~/.ssh`.
     //
-    // * If a username/password is allowed, then we fallback to git2-rs's
-    //   implementation of the credential helper. This is what is configured
-    //   with `credential.helper` in git, and is the interface for the OSX
-    //   keychain, for example.
+    // * If a username/password is allowed, then we fallback to the credential
+    //   helpers configured with `credential.helper` in git, which is the
+    //   interface for the OSX keychain, for example. Should the credentials
+    //   of one helper be rejected the next one configured is asked, so a
+    //   stale token in one doesn't shadow good credentials in another.
     //
     // * After the above two have failed, we just kinda grapple attempting to
     //   return *something*.
-    let mut cred_helper = git2::CredentialHelper::new(url);
-    cred_helper.config(cfg);
+    //
+    // Ssh urls may be written either as `ssh://user@host:port/path` or in the
+    // scp-like `user@host:path` form, which git treats as the same thing. Only
+    // the former is understood when looking up `credential.<url>` settings,
+    // so the latter is translated first.
+    let ssh_url = parse_ssh_url(url);
+    let cred_url = match ssh_url {
+        Some(ref ssh_url) => ssh_url.to_ssh_url(),
+        None => url.to_string(),
+    };
+    let url_username = ssh_url.and_then(|u| u.user);
+    let cred_username = cfgs.iter().filter_map(|cfg| {
+        let mut cred_helper = git2::CredentialHelper::new(cred_url.as_slice());
+        cred_helper.config(*cfg);
+        cred_helper.username
+    }).next();
+    let ssh_keys = try!(ssh_key_paths());
+    let mut ssh_attempts = 0u;
+    let helpers = credential_helpers(cfgs, cred_url.as_slice());
+    let mut helper_attempts = 0u;
+    let mut tried_userpass = Vec::new();
+    // Every credential handed to libgit2 along with the reason it failed, or
+    // `None` if it was created successfully.
+    let mut attempts: Vec<(String, Option<String>)> = Vec::new();
+    let mut allowed_types = git2::CredentialType::empty();
     let mut cred_error = false;
+    let mut provider_asked = false;
+    let mut calls = 0u;
     let ret = f(|url, username, allowed| {
-        let creds = if allowed.contains(git2::SSH_KEY) {
+        // Being asked for credentials again means the server rejected the
+        // ones we handed out last time.
+        if let Some(last) = attempts.last_mut() {
+            if last.1.is_none() {
+                last.1 = Some("rejected".to_string());
+            }
+        }
+        allowed_types = allowed_types | allowed;
+
+        // libgit2 keeps asking for as long as credentials are handed out, so
+        // put a stop to it rather than hammering the server.
+        calls += 1;
+        if calls > MAX_AUTH_ATTEMPTS {
+            cred_error = true;
+            return Err(git2::Error::from_str("too many authentication attempts"))
+        }
+
+        let provided = if provider_asked {
+            None
+        } else {
+            provider_asked = true;
+            provider.credentials(url, username, allowed)
+        };
+
+        let (attempt, creds) = if let Some(creds) = provided {
+            (Some("credential provider".to_string()), Ok(creds))
+        } else if allowed.contains(git2::SSH_KEY) {
             let user = username.map(|s| s.to_string())
-                               .or_else(|| cred_helper.username.clone())
+                               .or_else(|| url_username.clone())
+                               .or_else(|| cred_username.clone())
                                .unwrap_or("git".to_string());
-            git2::Cred::ssh_key_from_agent(user.as_slice())
+            ssh_attempts += 1;
+            if ssh_attempts == 1 {
+                (Some("ssh-agent".to_string()),
+                 git2::Cred::ssh_key_from_agent(user.as_slice()))
+            } else {
+                match ssh_keys.get(ssh_attempts - 2) {
+                    Some(key) => {
+                        (Some(format!("ssh key `{}`", key.display())),
+                         git2::Cred::ssh_key(user.as_slice(), None, key, None))
+                    }
+                    None => {
+                        (None, Err(git2::Error::from_str("no more ssh keys \
+                                                          to try")))
+                    }
+                }
+            }
         } else if allowed.contains(git2::USER_PASS_PLAINTEXT) {
-            git2::Cred::credential_helper(cfg, url, username)
+            let user = username.map(|s| s.to_string())
+                               .or_else(|| cred_username.clone());
+            let first = helper_attempts == 0;
+            let mut found = None;
+            while found.is_none() && helper_attempts < helpers.len() {
+                let helper = helpers[helper_attempts].clone();
+                helper_attempts += 1;
+                match run_credential_helper(helper.as_slice(), url,
+                                            user.as_ref().map(|s| s.as_slice())) {
+                    // Handing out what was already rejected is pointless
+                    Some(ref userpass) if tried_userpass.contains(userpass) => {}
+                    Some(userpass) => {
+                        tried_userpass.push(userpass.clone());
+                        found = Some((helper, userpass));
+                    }
+                    None => {}
+                }
+            }
+            match found {
+                Some((helper, (user, password))) => {
+                    (Some(format!("credential helper `{}`", helper)),
+                     git2::Cred::userpass_plaintext(user.as_slice(),
+                                                    password.as_slice()))
+                }
+                None if first => {
+                    let msg = if helpers.is_empty() {
+                        "no credential helper configured"
+                    } else {
+                        "no credentials from any credential helper"
+                    };
+                    (Some("credential helper".to_string()),
+                     Err(git2::Error::from_str(msg)))
+                }
+                None => {
+                    (None, Err(git2::Error::from_str("no more credential \
+                                                      helpers to try")))
+                }
+            }
         } else if allowed.contains(git2::DEFAULT) {
-            git2::Cred::default()
+            (Some("default credentials".to_string()), git2::Cred::default())
+        } else {
+            (None, Err(git2::Error::from_str("no authentication available")))
+        };
+        match attempt {
+            Some(attempt) => {
+                debug!("authenticating to {} with {}", url, attempt);
+                let err = creds.as_ref().err().map(|e| e.message().to_string());
+                attempts.push((attempt, err));
+            }
+            None => {}
+        }
+        cred_error = creds.is_err();
+        creds
+    });
+    if cred_error {
+        ret.map_err(|e| {
+            let mut msg = auth_error_message(attempts.as_slice(), allowed_types);
+            if calls > MAX_AUTH_ATTEMPTS {
+                msg = format!("authentication failed after {} attempts\n{}",
+                              MAX_AUTH_ATTEMPTS, msg);
+            }
+            GitError::new(GitErrorKind::Auth,
+                          human(msg).concrete().with_cause(e))
+        })
+    } else {
+        ret
+    }
+}
+
+/// Describes a failure to authenticate, listing each credential which was
+/// tried along with why it didn't work.
+fn auth_error_message(attempts: &[(String, Option<String>)],
+                      allowed: git2::CredentialType) -> String {
+    let mut msg = "Failed to authenticate when downloading \
+                   repository".to_string();
+    if attempts.len() == 0 {
+        msg.push_str("\nthe server didn't accept any supported type of \
+                      credentials");
+        return msg
+    }
+
+    let tried = attempts.iter().map(|&(ref attempt, ref err)| {
+        match *err {
+            Some(ref err) => format!("{} ({})", attempt, err),
+            None => attempt.clone(),
+        }
+    }).collect::<Vec<String>>();
+    msg.push_str(format!("\ntried {}", tried.connect(", ")).as_slice());
+
+    if allowed.contains(git2::SSH_KEY) {
+        msg.push_str("\nis your SSH key added to the agent with `ssh-add`?");
+    } else if allowed.contains(git2::USER_PASS_PLAINTEXT) {
+        msg.push_str("\nis a credential helper configured with \
+                      `credential.helper` in git?");
+    }
+    msg
+}
+
+/// The parts of an ssh url which matter when connecting and authenticating.
+#[deriving(PartialEq, Show)]
+struct SshUrl {
+    user: Option<String>,
+    host: String,
+    port: Option<u16>,
+    path: String,
+}
+
+impl SshUrl {
+    /// Formats this url as an `ssh://` url, whichever form it was parsed from.
+    fn to_ssh_url(&self) -> String {
+        let mut url = "ssh://".to_string();
+        match self.user {
+            Some(ref user) => url.push_str(format!("{}@", user).as_slice()),
+            None => {}
+        }
+        url.push_str(self.host.as_slice());
+        match self.port {
+            Some(port) => url.push_str(format!(":{}", port).as_slice()),
+            None => {}
+        }
+        if !self.path.as_slice().starts_with("/") {
+            url.push('/');
+        }
+        url.push_str(self.path.as_slice());
+        url
+    }
+}
+
+/// Splits `url` into its parts if it's an ssh url, either of the form
+/// `ssh://[user@]host[:port]/path` or the scp-like `[user@]host:path`.
+fn parse_ssh_url(url: &str) -> Option<SshUrl> {
+    let (authority, path, scp) = if url.starts_with("ssh://") {
+        let rest = url.slice_from("ssh://".len());
+        match rest.find('/') {
+            Some(i) => (rest.slice_to(i), rest.slice_from(i), false),
+            None => return None,
+        }
+    } else if url.contains("://") {
+        return None
+    } else {
+        // Anything before the first `:` is the host unless there's a `/` in
+        // it, in which case it's a local path like `./foo:bar`.
+        match url.find(':') {
+            Some(i) if !url.slice_to(i).contains("/") => {
+                (url.slice_to(i), url.slice_from(i + 1), true)
+            }
+            _ => return None,
+        }
+    };
+
+    let (user, host) = match authority.rfind('@') {
+        Some(i) => (Some(authority.slice_to(i).to_string()),
+                    authority.slice_from(i + 1)),
+        None => (None, authority),
+    };
+    // The scp-like form has no room for a port, the `:` ends the host.
+    let (host, port) = match host.rfind(':') {
+        Some(i) if !scp => {
+            match from_str::<u16>(host.slice_from(i + 1)) {
+                Some(port) => (host.slice_to(i), Some(port)),
+                None => return None,
+            }
+        }
+        _ => (host, None),
+    };
+    // A single letter "host" is a drive letter on Windows, as in `C:\foo`.
+    if host.len() == 0 || (scp && host.len() == 1) {
+        return None
+    }
+    Some(SshUrl {
+        user: user,
+        host: host.to_string(),
+        port: port,
+        path: path.to_string(),
+    })
+}
+
+/// Returns whether `url` is an SSH url which git would connect to through the
+/// command in GIT_SSH_COMMAND or GIT_SSH rather than plain `ssh`.
+fn uses_ssh_wrapper(url: &str) -> bool {
+    parse_ssh_url(url).is_some() &&
+        (os::getenv("GIT_SSH_COMMAND").is_some() || os::getenv("GIT_SSH").is_some())
+}
+
+/// Returns the command git should run ssh with to fetch `url`, or `None` if it
+/// isn't an SSH url or another command has been configured (through
+/// `GIT_SSH_COMMAND`, `GIT_SSH` or `core.sshCommand`) and should be left alone.
+///
+/// ssh checks the key of the host against `~/.ssh/known_hosts`. Hosts which
+/// aren't in there yet are added on first connection unless
+/// `git.accept-new-host-keys` is turned off, and hosts whose key has changed
+/// are always refused. There's no one to answer prompts, so ssh is told not to
+/// ask for anything.
+fn ssh_command(cfg: &git2::Config, config: &GitConfig, url: &str) -> Option<String> {
+    if parse_ssh_url(url).is_none() || uses_ssh_wrapper(url) ||
+       cfg.get_str("core.sshCommand").is_ok() {
+        return None
+    }
+    let checking = if config.accept_new_host_keys {"accept-new"} else {"yes"};
+    let mut command = format!("ssh -o BatchMode=yes -o StrictHostKeyChecking={}",
+                              checking);
+    match config.ssh_key {
+        Some(ref key) => command.push_str(format!(" -i '{}'", key.display()).as_slice()),
+        None => {}
+    }
+    Some(command)
+}
+
+/// Warns (once) if ssh-agent can't be reached or doesn't hold any keys while
+/// there are no keys on disk to fall back to either, as authenticating to `url`
+/// is bound to fail then with a rather generic error.
+fn check_ssh_agent(url: &str) -> CargoResult<()> {
+    if WARNED_SSH_AGENT.load(atomic::SeqCst) || try!(ssh_key_paths()).len() > 0 {
+        return Ok(())
+    }
+    let problem = if os::getenv("SSH_AUTH_SOCK").is_none() {
+        "ssh-agent isn't running; start it and run `ssh-add`"
+    } else {
+        // `ssh-add -l` exits with 1 if the agent has no keys, and with 2 if it
+        // couldn't be reached.
+        match try!(process("ssh-add")).arg("-l").build_command().output() {
+            Ok(ref out) if out.status.matches_exit_status(1) => {
+                "ssh-agent has no keys; run `ssh-add`"
+            }
+            Ok(ref out) if out.status.matches_exit_status(2) => {
+                "ssh-agent can't be reached; check that `SSH_AUTH_SOCK` is right"
+            }
+            _ => return Ok(()),
+        }
+    };
+    if !WARNED_SSH_AGENT.swap(true, atomic::SeqCst) {
+        warn(format!("{} (needed to authenticate to {})", problem, url));
+    }
+    Ok(())
+}
+
+/// Checks the key which the SSH server of a url presents against the keys
+/// recorded for it in `~/.ssh/known_hosts` (and `/etc/ssh/ssh_known_hosts`),
+/// through libgit2's `certificate_check` callback. libgit2 would connect to any
+/// host whatsoever otherwise.
+///
+/// libgit2 only hands out the SHA-1 fingerprint of the key the host presents,
+/// so the recorded keys are hashed to compare them. Hosts without any entry
+/// are trusted unless `git.accept-new-host-keys` is turned off, but aren't
+/// added to `known_hosts` as their key itself isn't known.
+struct HostKeyCheck {
+    /// The host as it's named in `known_hosts`, which is `[host]:port` for
+    /// ports other than 22.
+    host: String,
+    accept_new: bool,
+    /// Why the key of the host was refused, if it was.
+    failure: Option<String>,
+}
+
+/// What `known_hosts` says about a key presented by a host.
+#[deriving(PartialEq, Show)]
+enum HostKeyStatus {
+    /// It's recorded for the host.
+    Known,
+    /// Keys are recorded for the host, but not this one.
+    Changed,
+    /// It's recorded for the host, marked as `@revoked`.
+    Revoked,
+    /// No keys at all are recorded for the host.
+    Unknown,
+}
+
+impl HostKeyCheck {
+    /// Returns the check for `url`, or `None` if it isn't an SSH url.
+    fn new(url: &str, config: &GitConfig) -> Option<HostKeyCheck> {
+        parse_ssh_url(url).map(|ssh| HostKeyCheck {
+            host: match ssh.port {
+                Some(port) if port != 22 => format!("[{}]:{}", ssh.host, port),
+                _ => ssh.host,
+            },
+            accept_new: config.accept_new_host_keys,
+            failure: None,
+        })
+    }
+
+    /// Returns whether the connection may go ahead with the host presenting
+    /// `cert`, recording why not otherwise.
+    fn check(&mut self, cert: &git2::Cert) -> bool {
+        let fingerprint = match cert.as_hostkey().and_then(|key| key.hash_sha1()) {
+            Some(hash) => hash.as_slice().to_vec(),
+            None => {
+                self.failure = Some(format!("`{}` didn't present an SSH host key \
+                                             which could be checked", self.host));
+                return false
+            }
+        };
+        let mut known_hosts = String::new();
+        for file in known_hosts_files().iter() {
+            match File::open(file).read_to_string() {
+                Ok(contents) => known_hosts.push_str(contents.as_slice()),
+                Err(..) => {}
+            }
+            known_hosts.push('\n');
+        }
+        let status = host_key_status(known_hosts.as_slice(), self.host.as_slice(),
+                                     fingerprint.as_slice());
+        self.failure = match status {
+            HostKeyStatus::Known => None,
+            HostKeyStatus::Unknown if self.accept_new => {
+                info!("trusting the new SSH host `{}`, its key has the SHA-1 \
+                       fingerprint {}", self.host, to_hex(fingerprint.as_slice()));
+                None
+            }
+            HostKeyStatus::Unknown => {
+                Some(format!("`{}` isn't a known SSH host; add its key to \
+                              `~/.ssh/known_hosts` (such as by connecting with \
+                              `ssh` once), or set `git.accept-new-host-keys` to \
+                              trust new hosts on first connection", self.host))
+            }
+            HostKeyStatus::Changed => {
+                Some(format!("the host key presented by `{}` doesn't match the \
+                              one recorded in `~/.ssh/known_hosts`; someone could \
+                              be impersonating the host (a man-in-the-middle \
+                              attack), or its key was changed", self.host))
+            }
+            HostKeyStatus::Revoked => {
+                Some(format!("the host key presented by `{}` is marked as revoked \
+                              in `~/.ssh/known_hosts`", self.host))
+            }
+        };
+        self.failure.is_none()
+    }
+}
+
+/// Returns the error for the host key `check` refused, if it did.
+fn refused_host_key(check: Option<HostKeyCheck>) -> Option<GitError> {
+    check.and_then(|check| check.failure).map(|msg| {
+        GitError::new(GitErrorKind::Auth, human(msg))
+    })
+}
+
+/// The files listing the keys of known SSH hosts, like ssh reads them.
+fn known_hosts_files() -> Vec<Path> {
+    let mut files = Vec::new();
+    match os::homedir() {
+        Some(home) => files.push(home.join(".ssh").join("known_hosts")),
+        None => {}
+    }
+    files.push(Path::new("/etc/ssh/ssh_known_hosts"));
+    files
+}
+
+/// Looks up the key with the SHA-1 `fingerprint` presented by `host` in the
+/// lines of `known_hosts`.
+///
+/// Entries for the host are found by name (with `*` and `?` wildcards and `!`
+/// to negate), or by the hash of its name for hashed entries. Entries marked
+/// `@cert-authority` aren't understood and are ignored.
+fn host_key_status(known_hosts: &str, host: &str, fingerprint: &[u8]) -> HostKeyStatus {
+    let host = lowercase(host);
+    let (mut known, mut listed) = (false, false);
+    for line in known_hosts.lines() {
+        if line.trim_left().starts_with("#") { continue }
+        let words = line.words().collect::<Vec<&str>>();
+        let (marker, words) = match words.as_slice().head() {
+            Some(word) if word.starts_with("@") => {
+                (Some(*word), words.as_slice().slice_from(1))
+            }
+            _ => (None, words.as_slice()),
+        };
+        if words.len() < 3 || !host_patterns_match(words[0], host.as_slice()) {
+            continue
+        }
+        let matches = words[2].from_base64().ok().map_or(false, |key| {
+            let mut sha1 = Sha1::new();
+            sha1.update(key.as_slice());
+            sha1.finish().as_slice() == fingerprint
+        });
+        match marker {
+            Some("@revoked") if matches => return HostKeyStatus::Revoked,
+            Some(..) => {}
+            None => {
+                listed = true;
+                known = known || matches;
+            }
+        }
+    }
+    if known {
+        HostKeyStatus::Known
+    } else if listed {
+        HostKeyStatus::Changed
+    } else {
+        HostKeyStatus::Unknown
+    }
+}
+
+/// Returns whether the comma-separated host `patterns` of a `known_hosts` entry
+/// match `host`, or for a hashed entry (`|1|salt|hash`) whether it's the hash
+/// of `host`.
+fn host_patterns_match(patterns: &str, host: &str) -> bool {
+    if patterns.starts_with("|1|") {
+        let parts = patterns.slice_from(3).split('|').collect::<Vec<&str>>();
+        if parts.len() != 2 { return false }
+        return match (parts[0].from_base64(), parts[1].from_base64()) {
+            (Ok(salt), Ok(hash)) => {
+                hmac_sha1(salt.as_slice(), host.as_bytes()).as_slice() == hash.as_slice()
+            }
+            _ => false,
+        }
+    }
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        let (negated, pattern) = if pattern.starts_with("!") {
+            (true, pattern.slice_from(1))
         } else {
-            Err(git2::Error::from_str("no authentication available"))
+            (false, pattern)
         };
-        cred_error = creds.is_err();
-        creds
-    });
-    if cred_error {
-        ret.chain_error(|| {
-            human("Failed to authenticate when downloading repository")
-        })
+        if wildcard_matches(lowercase(pattern).as_bytes(), host.as_bytes()) {
+            // A negated pattern which matches rules the entry out altogether
+            if negated { return false }
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// Matches `s` against `pattern`, in which `*` stands for any number of
+/// characters and `?` for exactly one.
+fn wildcard_matches(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.head() {
+        None => s.is_empty(),
+        Some(&b'*') => {
+            range(0, s.len() + 1).any(|i| wildcard_matches(pattern.tail(), s.slice_from(i)))
+        }
+        Some(&c) => match s.head() {
+            Some(&d) if c == b'?' || c == d => {
+                wildcard_matches(pattern.tail(), s.tail())
+            }
+            _ => false,
+        },
+    }
+}
+
+/// The HMAC-SHA1 of `msg` with `key`, which is what the names of hosts in
+/// hashed `known_hosts` entries are.
+fn hmac_sha1(key: &[u8], msg: &[u8]) -> [u8, ..20] {
+    // Keys longer than a block are hashed down first
+    let mut block = [0u8, ..64];
+    let key = if key.len() > block.len() {
+        let mut sha1 = Sha1::new();
+        sha1.update(key);
+        sha1.finish().as_slice().to_vec()
     } else {
-        ret
+        key.to_vec()
+    };
+    for (b, k) in block.iter_mut().zip(key.iter()) {
+        *b = *k;
     }
+    let mut inner = Sha1::new();
+    inner.update(block.iter().map(|b| *b ^ 0x36).collect::<Vec<u8>>().as_slice());
+    inner.update(msg);
+    let inner = inner.finish();
+    let mut outer = Sha1::new();
+    outer.update(block.iter().map(|b| *b ^ 0x5c).collect::<Vec<u8>>().as_slice());
+    outer.update(inner.as_slice());
+    outer.finish()
 }
 
+/// Returns the private SSH keys on disk which may be used to authenticate, in
+/// the order in which they should be tried.
+fn ssh_key_paths() -> CargoResult<Vec<Path>> {
+    let mut keys = Vec::new();
+    match try!(git_configuration()).ssh_key {
+        Some(key) => keys.push(key),
+        None => {}
+    }
+    match os::homedir() {
+        Some(home) => {
+            for name in ["id_rsa", "id_ed25519"].iter() {
+                let key = home.join(".ssh").join(*name);
+                if key.exists() && !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+        None => {}
+    }
+    Ok(keys)
+}
+
+/// Fetches `refspec` (and all tags, unless it's a single tag) from `url` into
+/// `repo`.
+///
+/// Tags are always fetched with a forced refspec, so a tag which was moved
+/// upstream is moved locally as well rather than staying stuck on its old
+/// target.
+///
+/// Fetches which fail due to network errors are retried a few times with an
+/// increasing delay, as configured by `git.retry`. If `git.fetch-with-cli` is
+/// set then the `git` binary does all the work instead, using the user's own
+/// git configuration and credential helpers. So does it for SSH urls when
+/// GIT_SSH_COMMAND or GIT_SSH is set, for the SSH wrapper to be used, and for
+/// http(s) urls when settings libgit2 lacks such as `http.postBuffer` are.
+///
+/// Returns how much was transferred, which is only known if libgit2 did the
+/// fetching rather than the `git` binary.
 pub fn fetch(repo: &git2::Repository, url: &str,
-             refspec: &str) -> CargoResult<()> {
+             refspec: &str) -> GitResult<Option<FetchStats>> {
+    fetch_with_parent(repo, None, url, refspec, None)
+}
+
+/// Same as `fetch`, but `provider` gets the first say in which credentials are
+/// used to authenticate.
+///
+/// This is an error if the fetch would be done with the `git` binary (because
+/// of `git.fetch-with-cli`, an SSH wrapper or settings libgit2 lacks), as
+/// there's no handing the provider's credentials to it.
+pub fn fetch_with_credentials(repo: &git2::Repository, url: &str, refspec: &str,
+                              provider: &mut CredentialProvider)
+                              -> GitResult<Option<FetchStats>> {
+    fetch_with_parent(repo, None, url, refspec, Some(provider))
+}
+
+/// The error for a fetch of `url` with a `CredentialProvider` which would have
+/// to be done with the `git` binary.
+fn provider_needs_libgit2(url: &str) -> GitError {
+    GitError::new(GitErrorKind::Auth,
+                  human(format!("credentials from a credential provider can't \
+                                 be used to fetch `{}`, it has to be fetched \
+                                 with the `git` executable", url)))
+}
+
+/// Same as `fetch_with_credentials`, but credentials which can't be found
+/// through the configuration of `repo` are also looked for in `parent_cfg`,
+/// the configuration of the repository `repo` is a submodule of.
+///
+/// The configuration is read just once, however many times the fetch is
+/// retried.
+fn fetch_with_parent(repo: &git2::Repository,
+                     parent_cfg: Option<&git2::Config>,
+                     url: &str, refspec: &str,
+                     provider: Option<&mut CredentialProvider>)
+                     -> GitResult<Option<FetchStats>> {
+    let config = try!(git_configuration());
+    let mut refspecs = vec![refspec];
+    refspecs.extend(config.extra_refspecs(url).iter().map(|r| r.as_slice()));
+    let refspecs = refspecs.as_slice();
+    let custom = provider.is_some();
+    if config.fetch_with_cli {
+        if custom { return Err(provider_needs_libgit2(url)) }
+        try!(fetch_with_cli(repo, url, refspecs, &[]).map_err(|e| {
+            e.context(human(format!("failed to fetch `{}` with `git fetch`", url)))
+        }));
+        record_fetch(None);
+        return Ok(None)
+    }
+    let cfg = match repo.config() {
+        Ok(cfg) => cfg,
+        Err(..) => {
+            try!(repair_config(repo.path(), repo.is_bare()));
+            try!(repo.config())
+        }
+    };
+    let mut cfgs = vec![&cfg];
+    cfgs.extend(parent_cfg.into_iter());
+    let cfgs = cfgs.as_slice();
+    let mut none = NoCredentials;
+    let provider = match provider {
+        Some(provider) => provider,
+        None => &mut none as &mut CredentialProvider,
+    };
+
+    let mut remaining = config.retries;
+    let mut delay = 500;
+    loop {
+        match fetch_once(repo, cfgs, &config, url, refspecs, &mut *provider,
+                         custom) {
+            Err(ref e) if e.kind() == GitErrorKind::Network && remaining > 0 => {
+                info!("fetch of {} failed, retrying in {}ms: {}", url, delay,
+                      e.description());
+                timer::sleep(Duration::milliseconds(delay));
+                delay *= 2;
+                remaining -= 1;
+            }
+            Ok(Some(stats)) => {
+                debug!("fetched {}: {}/{} objects received ({} bytes), {} local",
+                      url, stats.received_objects, stats.total_objects,
+                      stats.received_bytes, stats.local_objects);
+                record_fetch(Some(&stats));
+                return Ok(Some(stats))
+            }
+            Ok(None) => {
+                record_fetch(None);
+                return Ok(None)
+            }
+            ret => return ret,
+        }
+    }
+}
+
+/// Adds a fetch which transferred `stats` (if known) to the `fetch_totals`.
+fn record_fetch(stats: Option<&FetchStats>) {
+    FETCHED_REPOSITORIES.fetch_add(1, atomic::SeqCst);
+    match stats {
+        Some(stats) => {
+            FETCHED_OBJECTS.fetch_add(stats.received_objects, atomic::SeqCst);
+            FETCHED_BYTES.fetch_add(stats.received_bytes, atomic::SeqCst);
+        }
+        None => {}
+    }
+}
+
+/// Returns how much all fetches of git repositories made by this process have
+/// transferred so far.
+pub fn fetch_totals() -> FetchTotals {
+    FetchTotals {
+        repositories: FETCHED_REPOSITORIES.load(atomic::SeqCst),
+        received_objects: FETCHED_OBJECTS.load(atomic::SeqCst),
+        received_bytes: FETCHED_BYTES.load(atomic::SeqCst),
+    }
+}
+
+/// Fetches once with libgit2 where it's able to, where `cfgs` is the
+/// configuration of `repo` followed by that of its parent repository, if any.
+/// `custom` is whether `provider` came from the caller of
+/// `fetch_with_credentials`, in which case the `git` binary can't be used.
+fn fetch_once(repo: &git2::Repository, cfgs: &[&git2::Config],
+              config: &GitConfig, url: &str, refspecs: &[&str],
+              provider: &mut CredentialProvider, custom: bool)
+              -> GitResult<Option<FetchStats>> {
+    let cfg = cfgs[0];
+    let url = rewrite_url(cfg, url);
+    let url = url.as_slice();
+    debug!("fetching {} from {}", refspecs, url);
+
+    if uses_cli(cfg, config, url) {
+        if custom { return Err(provider_needs_libgit2(url)) }
+        if !ssl_verify(cfg, url) {
+            warn(format!("certificate verification is disabled for {}, it may \
+                          be insecure", url));
+        }
+        match http_tuning(cfg, url) {
+            Some(key) => info!("fetching {} with git, libgit2 doesn't support \
+                                `{}`", url, key),
+            None => {}
+        }
+        if parse_ssh_url(url).is_some() {
+            try!(check_ssh_agent(url));
+        }
+        try!(fetch_with_cli(repo, url, refspecs, &[]));
+        return Ok(None)
+    }
+
     // Create a local anonymous remote in the repository to fetch the url
+    with_authentication(url, cfgs, provider, |f| {
+        let mut progress = FetchProgress::new();
+        let mut host_key = HostKeyCheck::new(url, config);
+        let ret = {
+            let mut cb = git2::RemoteCallbacks::new()
+                .credentials(f)
+                .transfer_progress(|p| progress.tick(p));
+            match host_key {
+                Some(ref mut check) => {
+                    cb = cb.certificate_check(|cert, _host| check.check(cert));
+                }
+                None => {}
+            }
+            let mut remote = try!(repo.remote_anonymous(url.as_slice(),
+                                                        refspecs[0]));
+            let mut all = Vec::new();
+            if needs_all_tags(refspecs, config) {
+                try!(remote.add_fetch(TAGS_REFSPEC));
+                all.push(TAGS_REFSPEC);
+            }
+            remote.set_callbacks(&mut cb);
+            all.push_all(refspecs);
+            remote.fetch(all.as_slice(), None, None)
+        };
+        match refused_host_key(host_key) {
+            Some(err) => return Err(err),
+            None => {}
+        }
+        try!(ret.map_err(|e| http_status_error(url, e)));
+        Ok(Some(progress.stats))
+    })
+}
 
-    with_authentication(url, &try!(repo.config()), |f| {
-        let mut cb = git2::RemoteCallbacks::new()
-                                       .credentials(f);
-        let mut remote = try!(repo.remote_anonymous(url.as_slice(), refspec));
-        try!(remote.add_fetch("refs/tags/*:refs/tags/*"));
-        remote.set_callbacks(&mut cb);
-        try!(remote.fetch(&["refs/tags/*:refs/tags/*", refspec], None, None));
-        Ok(())
+/// Returns whether fetching `url` has to be left to the `git` binary, as it
+/// needs something which libgit2 can't do.
+fn uses_cli(cfg: &git2::Config, config: &GitConfig, url: &str) -> bool {
+    // Asked for with `git.fetch-with-cli`
+    config.fetch_with_cli ||
+    // libgit2 doesn't know how to talk through a proxy, but git itself does
+    // (and reads the same configuration).
+    http_proxy(cfg, config, url).is_some() ||
+    // Neither can it send a different user agent or extra headers.
+    config.http_options(url).len() > 0 ||
+    // The version of libgit2 we use has no way to skip verifying certificates.
+    !ssl_verify(cfg, url) ||
+    // Nor does it have any of the knobs git has for transferring very large
+    // objects over http.
+    http_tuning(cfg, url).is_some() ||
+    // Nor can it connect to SSH hosts through a wrapper (say, to go through a
+    // jump host) named by GIT_SSH_COMMAND or GIT_SSH.
+    uses_ssh_wrapper(url)
+}
+
+/// Applies any `url.<base>.insteadOf` rewrites configured in git to `url`, just
+/// like git itself would before fetching.
+///
+/// If multiple prefixes match then the longest one wins. `pushInsteadOf` is
+/// ignored as it only applies to pushes, which cargo never does.
+fn rewrite_url(cfg: &git2::Config, url: &str) -> String {
+    let entries = match cfg.entries(Some("url\\..*\\.insteadof")) {
+        Ok(entries) => entries,
+        Err(..) => return url.to_string(),
+    };
+    let mut best: Option<(uint, String)> = None;
+    for entry in entries {
+        let (name, prefix) = match (entry.name(), entry.value()) {
+            (Some(name), Some(prefix)) => (name, prefix),
+            _ => continue,
+        };
+        if !url.starts_with(prefix) { continue }
+        match best {
+            Some((len, _)) if len >= prefix.len() => continue,
+            _ => {}
+        }
+        // Strip the `url.` and `.insteadof` surrounding the base url
+        let base = name.slice(4, name.len() - 10);
+        best = Some((prefix.len(),
+                     format!("{}{}", base, url.slice_from(prefix.len()))));
+    }
+    match best {
+        Some((_, rewritten)) => {
+            info!("rewrote {} to {}", url, rewritten);
+            rewritten
+        }
+        None => url.to_string(),
+    }
+}
+
+/// Lists the credential helpers configured for `url` in each of `cfgs`, in the
+/// order git would ask them.
+///
+/// Both `credential.helper` and `credential.<url>.helper` for urls matching
+/// `url` count, and just like in git an empty helper clears the ones before it.
+fn credential_helpers(cfgs: &[&git2::Config], url: &str) -> Vec<String> {
+    let url = Url::parse(url).ok();
+    let mut helpers = Vec::new();
+    for cfg in cfgs.iter() {
+        let entries = match cfg.entries(Some("credential\\..*helper")) {
+            Ok(entries) => entries,
+            Err(..) => continue,
+        };
+        for entry in entries {
+            let (name, helper) = match (entry.name(), entry.value()) {
+                (Some(name), Some(helper)) => (name, helper),
+                _ => continue,
+            };
+            let applies = name == "credential.helper" || match url {
+                // Strip the `credential.` and `.helper` around the url
+                Some(ref url) if name.len() > 17 => {
+                    credential_url_matches(name.slice(11, name.len() - 7), url)
+                }
+                _ => false,
+            };
+            if !applies { continue }
+            if helper.is_empty() {
+                helpers.clear();
+            } else if !helpers.iter().any(|h: &String| h.as_slice() == helper) {
+                helpers.push(helper.to_string());
+            }
+        }
+    }
+    helpers
+}
+
+/// Returns whether `credential.<pattern>.helper` applies to `url`, which is
+/// the case if the scheme, host and port are the same and the path of
+/// `pattern` (if it has any) is made of the leading segments of that of `url`.
+fn credential_url_matches(pattern: &str, url: &Url) -> bool {
+    let pattern = match Url::parse(pattern) {
+        Ok(pattern) => pattern,
+        Err(..) => return false,
+    };
+    if pattern.scheme != url.scheme || pattern.host() != url.host() ||
+       pattern.port() != url.port() {
+        return false
+    }
+    match pattern.username() {
+        Some(user) if user != "" && url.username() != Some(user) => return false,
+        _ => {}
+    }
+    // Trailing slashes leave empty segments behind
+    let segments = |url: &Url| {
+        url.path().unwrap_or(&[]).iter().filter(|s| !s.is_empty())
+           .map(|s| s.clone()).collect::<Vec<String>>()
+    };
+    let prefix = segments(&pattern);
+    let path = segments(url);
+    prefix.len() <= path.len() && prefix.iter().zip(path.iter()).all(|(a, b)| a == b)
+}
+
+/// Asks the credential `helper` for a username and password for `url` the way
+/// git would, returning `None` if it has nothing to offer.
+///
+/// Helpers starting with `!` are shell snippets, which are run with `sh`.
+/// Otherwise the first word is an absolute path to run or names a
+/// `git credential-<helper>` command, and any others are passed to it as
+/// arguments.
+fn run_credential_helper(helper: &str, url: &str, username: Option<&str>)
+                         -> Option<(String, String)> {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(..) => return None,
+    };
+    let host = match (url.host(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return None,
+    };
+
+    let mut input = format!("protocol={}\nhost={}\n", url.scheme, host);
+    match username {
+        Some(user) => input.push_str(format!("username={}\n", user).as_slice()),
+        None => {}
+    }
+    input.push_str("\n");
+
+    let cmd = if helper.starts_with("!") {
+        process("sh").map(|cmd| {
+            cmd.arg("-c").arg(format!("{} get", helper.slice_from(1)))
+        })
+    } else {
+        let mut words = helper.split(' ').filter(|word| !word.is_empty());
+        let name = words.next().unwrap_or("");
+        let cmd = if Path::new(name).is_absolute() {
+            process(name)
+        } else {
+            process("git").map(|cmd| cmd.arg(format!("credential-{}", name)))
+        };
+        let args = words.collect::<Vec<&str>>();
+        cmd.map(|cmd| cmd.args(args.as_slice()).arg("get"))
+    };
+    let cmd = match cmd {
+        Ok(cmd) => cmd.build_command(),
+        Err(..) => return None,
+    };
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            info!("failed to run credential helper `{}`: {}", helper, e);
+            return None
+        }
+    };
+    // Dropping stdin closes it, which tells the helper that's all there is
+    match child.stdin.take() {
+        Some(mut stdin) => { let _ = stdin.write_str(input.as_slice()); }
+        None => {}
+    }
+    let output = match child.wait_with_output() {
+        Ok(ref output) if output.status.success() => {
+            String::from_utf8_lossy(output.output.as_slice()).into_owned()
+        }
+        _ => return None,
+    };
+
+    let mut user = username.map(|s| s.to_string());
+    let mut password = None;
+    for line in output.as_slice().lines() {
+        if line.starts_with("username=") {
+            user = Some(line.slice_from(9).to_string());
+        } else if line.starts_with("password=") {
+            password = Some(line.slice_from(9).to_string());
+        }
+    }
+    match (user, password) {
+        (Some(user), Some(password)) => Some((user, password)),
+        _ => None,
+    }
+}
+
+/// Finds the proxy which should be used to fetch from `url`, if any.
+///
+/// Proxies only apply to http(s) urls whose host isn't excluded by NO_PROXY.
+/// Favor cargo's `http.proxy`, then git's `http.proxy`, then the env vars for
+/// the url's scheme (HTTPS_PROXY or http_proxy) and finally ALL_PROXY, the way
+/// curl picks them.
+fn http_proxy(cfg: &git2::Config, config: &GitConfig, url: &str) -> Option<String> {
+    let vars = if url.starts_with("https://") {
+        vec!["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+    } else if url.starts_with("http://") {
+        // Like curl, HTTP_PROXY isn't read as CGI programs get it set from the
+        // `Proxy` header of the requests they serve.
+        vec!["http_proxy", "ALL_PROXY", "all_proxy"]
+    } else {
+        return None
+    };
+    if no_proxy(url) {
+        return None
+    }
+    match config.http_proxy {
+        Some(ref proxy) => return Some(proxy.clone()),
+        None => {}
+    }
+    match cfg.get_str("http.proxy") {
+        Ok(s) => return Some(s.to_string()),
+        Err(..) => {}
+    }
+    for var in vars.iter() {
+        match os::getenv(*var) {
+            Some(ref proxy) if !proxy.is_empty() => return Some(proxy.clone()),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns whether the host of `url` is one of those which NO_PROXY (or
+/// no_proxy) says are reached without going through a proxy.
+fn no_proxy(url: &str) -> bool {
+    let hosts = match os::getenv("NO_PROXY").or_else(|| os::getenv("no_proxy")) {
+        Some(hosts) => hosts,
+        None => return false,
+    };
+    match Url::parse(url).ok().and_then(|url| url.serialize_host()) {
+        Some(host) => no_proxy_matches(hosts.as_slice(), host.as_slice()),
+        None => false,
+    }
+}
+
+/// Returns whether `host` is matched by `no_proxy`, a comma separated list of
+/// hosts which also match their subdomains (with or without a leading `.`),
+/// or `*` for all hosts.
+fn no_proxy_matches(no_proxy: &str, host: &str) -> bool {
+    let host = lowercase(host);
+    no_proxy.split(',').map(|entry| entry.trim()).any(|entry| {
+        if entry == "*" { return true }
+        let entry = lowercase(entry.trim_left_chars('.'));
+        !entry.is_empty() &&
+            (host == entry || host.ends_with(format!(".{}", entry).as_slice()))
+    })
+}
+
+/// Returns whether the certificate of the server at `url` should be verified,
+/// which can be turned off with git's `http.sslVerify` or the
+/// GIT_SSL_NO_VERIFY env var.
+fn ssl_verify(cfg: &git2::Config, url: &str) -> bool {
+    if !url.starts_with("https://") {
+        return true
+    }
+    if os::getenv("GIT_SSL_NO_VERIFY").is_some() {
+        return false
+    }
+    cfg.get_bool("http.sslVerify").unwrap_or(true)
+}
+
+/// Returns the first of git's settings for big http transfers (such as
+/// `http.postBuffer`, which repositories with large objects often need) that's
+/// configured, if `url` is fetched over http(s) at all.
+fn http_tuning(cfg: &git2::Config, url: &str) -> Option<&'static str> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return None
+    }
+    let keys = ["http.postBuffer", "http.lowSpeedLimit", "http.lowSpeedTime"];
+    keys.iter().map(|key| *key).find(|key| cfg.get_i64(*key).is_ok())
+}
+
+/// Returns whether `err` reports that objects of the repository are damaged.
+fn is_corruption(err: &git2::Error) -> bool {
+    match err.class() {
+        git2::ErrorClass::Odb |
+        git2::ErrorClass::Zlib |
+        git2::ErrorClass::Object => true,
+        _ => false,
+    }
+}
+
+/// Returns whether `err` is likely to be a spurious failure of the network
+/// which could go away if the operation were attempted again.
+///
+/// TLS failures and HTTP client errors (such as a 401 or 404) are the server
+/// telling us something which won't change just by trying again.
+fn is_network_error(err: &git2::Error) -> bool {
+    match err.class() {
+        git2::ErrorClass::Net |
+        git2::ErrorClass::Os => {}
+        _ => return false,
+    }
+    match http_status(err.message().as_slice(), "status code: ") {
+        Some(status) => status < 400 || status >= 500,
+        None => true,
+    }
+}
+
+/// Finds the HTTP status code which follows `prefix` in the message `msg`.
+///
+/// Neither libgit2 nor git report the status in any other way, so the message
+/// has to be picked apart.
+fn http_status(msg: &str, prefix: &str) -> Option<uint> {
+    msg.find_str(prefix).and_then(|i| {
+        let rest = msg.slice_from(i + prefix.len());
+        let end = rest.find(|c: char| !c.is_digit()).unwrap_or(rest.len());
+        from_str::<uint>(rest.slice_to(end))
     })
 }
+
+/// Explains the HTTP status a fetch of `url` failed with, if it failed with a
+/// status saying that the repository doesn't exist or can't be accessed.
+fn http_status_error(url: &str, err: git2::Error) -> GitError {
+    match status_error(url, http_status(err.message().as_slice(), "status code: ")) {
+        Some((kind, msg)) => GitError::new(kind, msg.concrete().with_cause(err)),
+        None => FromError::from_error(err),
+    }
+}
+
+/// Does the same as `http_status_error` for the `git` binary, which failed to
+/// fetch `url` with `err`, going by what it printed.
+///
+/// git translates its messages, so this relies on it having been run with
+/// `LC_ALL=C`.
+fn cli_status_error(url: &str, err: ProcessError) -> GitError {
+    let stderr = err.output.as_ref().map(|output| {
+        String::from_utf8_lossy(output.error.as_slice()).into_owned()
+    }).unwrap_or(String::new());
+    let stderr = stderr.as_slice();
+    // "fatal: repository '<url>' not found" and "fatal: Authentication failed
+    // for '<url>'" are how smart http servers' 404s and 401s are reported,
+    // anything else shows up as "The requested URL returned error: <status>".
+    let status = if stderr.contains("fatal: repository") && stderr.contains("not found") {
+        Some(404)
+    } else if stderr.contains("fatal: Authentication failed") {
+        Some(401)
+    } else {
+        http_status(stderr, "returned error: ")
+    };
+    match status_error(url, status) {
+        Some((kind, msg)) => GitError::new(kind, msg.concrete().with_cause(err)),
+        None => FromError::from_error(box err as Box<CargoError>),
+    }
+}
+
+/// Returns the kind of error and the explanation for a fetch of `url` which
+/// failed with HTTP `status`, for statuses saying that the repository doesn't
+/// exist or can't be accessed.
+fn status_error(url: &str, status: Option<uint>)
+                -> Option<(GitErrorKind, Box<CargoError>)> {
+    match status {
+        Some(404) => {
+            Some((GitErrorKind::NotFound,
+                  human(format!("repository `{}` not found", url))))
+        }
+        Some(401) | Some(403) => {
+            Some((GitErrorKind::Auth,
+                  human(format!("access denied to `{}`; check your \
+                                 credentials", url))))
+        }
+        _ => None,
+    }
+}
+
+/// Fetches `refspecs` (and all tags, unless they're for a single tag) from `url`
+/// into `repo` by running the `git` binary, passing along any `extra` arguments
+/// to `git fetch`.
+///
+/// This is used for operations which libgit2 doesn't support, such as shallow
+/// fetches, and whenever `git.fetch-with-cli` is set.
+fn fetch_with_cli(repo: &git2::Repository, url: &str, refspecs: &[&str],
+                  extra: &[&str]) -> GitResult<()> {
+    let config = try!(git_configuration());
+    let mut cmd = try!(git_with_options(config.http_options(url).as_slice()));
+    let cfg = try!(repo.config());
+    match ssh_command(&cfg, &config, url) {
+        Some(ssh) => cmd = cmd.env("GIT_SSH_COMMAND", Some(ssh.as_slice())),
+        None => {}
+    }
+    // The progress is only asked for to find out how much was received
+    cmd = cmd.arg("fetch").arg("--progress").arg("--force").args(extra).arg(url);
+    if needs_all_tags(refspecs, &config) {
+        cmd = cmd.arg(TAGS_REFSPEC);
+    }
+    debug!("fetching {} from {} with git", refspecs, url);
+    let ret = cmd.args(refspecs)
+                 .cwd(repo.path().clone())
+                 .env("LC_ALL", Some("C"))
+                 .exec_with_output();
+    match ret {
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(output.error.as_slice()).into_owned();
+            match received_objects(stderr.as_slice()) {
+                Some(objects) => debug!("fetched {} with git: {} objects received",
+                                        url, objects),
+                None => debug!("fetched {} with git: nothing received", url),
+            }
+            Ok(())
+        }
+        Err(e) => Err(cli_status_error(url, e)),
+    }
+}
+
+/// Finds how many objects `git fetch --progress` received in what it printed,
+/// from the last of its `Receiving objects: 100% (12/12), ...` lines.
+fn received_objects(stderr: &str) -> Option<uint> {
+    if !stderr.contains("Receiving objects:") {
+        return None
+    }
+    let progress = stderr.split_str("Receiving objects:").last().unwrap_or("");
+    let counts = match progress.find('(') {
+        Some(i) => progress.slice_from(i + 1),
+        None => return None,
+    };
+    counts.split('/').next().and_then(|n| from_str::<uint>(n))
+}
+
+/// Returns a command running `git` with the configuration `options` (keys and
+/// values) set on top of the user's.
+///
+/// Headers may well carry credentials, so rather than as `-c` arguments (which
+/// end up in error messages and are visible to other users) the options are
+/// passed through the `GIT_CONFIG_KEY_<n>` and `GIT_CONFIG_VALUE_<n>`
+/// environment variables, after any which are set already.
+fn git_with_options(options: &[(String, String)]) -> CargoResult<ProcessBuilder> {
+    let mut cmd = try!(process("git"));
+    if options.len() == 0 {
+        return Ok(cmd)
+    }
+    let existing = os::getenv("GIT_CONFIG_COUNT").and_then(|n| {
+        from_str::<uint>(n.as_slice())
+    }).unwrap_or(0);
+    for (i, &(ref key, ref value)) in options.iter().enumerate() {
+        let n = existing + i;
+        cmd = cmd.env(format!("GIT_CONFIG_KEY_{}", n).as_slice(), Some(key.as_slice()))
+                 .env(format!("GIT_CONFIG_VALUE_{}", n).as_slice(),
+                      Some(value.as_slice()));
+    }
+    let count = (existing + options.len()).to_string();
+    Ok(cmd.env("GIT_CONFIG_COUNT", Some(count.as_slice())))
+}
+
+/// Returns whether every tag has to be fetched along with `refspecs`, which is
+/// the case unless just a single tag is being fetched.
+///
+/// With `git.case-insensitive-refs` set a tag may go by another name upstream,
+/// so all tags are fetched to find it.
+fn needs_all_tags(refspecs: &[&str], config: &GitConfig) -> bool {
+    config.case_insensitive_refs || match refspecs.head() {
+        Some(refspec) => !refspec.starts_with("+refs/tags/"),
+        None => true,
+    }
+}
+
+/// Reports the progress of a fetch on stderr, through a shell configured as
+/// `show_progress` asked for.
+///
+/// Updates are throttled to a few per second, and nothing at all is printed if
+/// stderr isn't a TTY or until it's known how many objects there are.
+struct FetchProgress {
+    shell: Option<Shell>,
+    printed: bool,
+    last_update: u64,
+    stats: FetchStats,
+}
+
+impl FetchProgress {
+    fn new() -> FetchProgress {
+        let config = PROGRESS.with(|progress| progress.get());
+        let shell = match config {
+            Some(config) if config.tty => {
+                Some(Shell::create(box stderr() as Box<Writer + Send>, config))
+            }
+            _ => None,
+        };
+        FetchProgress {
+            shell: shell,
+            printed: false,
+            last_update: 0,
+            stats: FetchStats {
+                received_objects: 0,
+                total_objects: 0,
+                received_bytes: 0,
+                local_objects: 0,
+            },
+        }
+    }
+
+    fn tick(&mut self, stats: git2::Progress) -> bool {
+        // The last update libgit2 sends is the final tally of the fetch
+        self.stats = FetchStats {
+            received_objects: stats.received_objects(),
+            total_objects: stats.total_objects(),
+            received_bytes: stats.received_bytes(),
+            local_objects: stats.local_objects(),
+        };
+        let shell = match self.shell {
+            Some(ref mut shell) => shell,
+            None => return true,
+        };
+        // Until the server says how much it's sending there's nothing to show
+        if stats.total_objects() == 0 { return true }
+
+        let now = time::precise_time_ns();
+        let done = stats.received_objects() == stats.total_objects();
+        if !done && now - self.last_update < 200_000_000 { return true }
+        self.last_update = now;
+
+        // Failing to print progress shouldn't fail the fetch itself
+        let _ = shell.update_status("Fetching",
+                                    format!("{}/{} objects ({} KiB)",
+                                            stats.received_objects(),
+                                            stats.total_objects(),
+                                            stats.received_bytes() / 1024),
+                                    GREEN);
+        self.printed = true;
+        true
+    }
+}
+
+impl Drop for FetchProgress {
+    fn drop(&mut self) {
+        if !self.printed { return }
+        match self.shell {
+            Some(ref mut shell) => { let _ = shell.write_line(""); }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use git2;
+    use super::{resolve_submodule_url, parse_ssh_url, SshUrl, GitRevision};
+    use super::{GitRemote, run_credential_helper, signing_key, credential_url_matches};
+    use super::{RepositoryLock, is_disk_full, head_branch};
+    use super::{git_with_options, http_status, move_into_place, received_objects};
+    use super::{http_tuning, no_proxy_matches};
+    use super::{host_key_status, hmac_sha1, HostKeyStatus};
+    use util::{Sha1, to_hex};
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+    use std::io::{TempDir, File, USER_DIR};
+    use std::io::fs::{mod, PathExtensions};
+    use util::FileLock;
+    use rustc_serialize::json;
+    use std::hash;
+    use util::ToUrl;
+    use url::Url;
+
+    #[test]
+    fn absolute_submodule_urls_are_untouched() {
+        let url = resolve_submodule_url("https://example.com/foo/bar.git",
+                                        "https://example.com/baz.git");
+        assert_eq!(url.as_slice(), "https://example.com/baz.git");
+    }
+
+    #[test]
+    fn relative_submodule_urls() {
+        let base = "https://example.com/foo/bar.git";
+        assert_eq!(resolve_submodule_url(base, "../baz.git").as_slice(),
+                   "https://example.com/foo/baz.git");
+        assert_eq!(resolve_submodule_url(base, "../../baz.git").as_slice(),
+                   "https://example.com/baz.git");
+        assert_eq!(resolve_submodule_url(base, "./baz").as_slice(),
+                   "https://example.com/foo/bar.git/baz");
+    }
+
+    #[test]
+    fn relative_submodule_urls_scp_like() {
+        assert_eq!(resolve_submodule_url("git@example.com:foo/bar.git",
+                                         "../baz.git").as_slice(),
+                   "git@example.com:foo/baz.git");
+        assert_eq!(resolve_submodule_url("git@example.com:bar.git",
+                                         "../baz.git").as_slice(),
+                   "git@example.com:baz.git");
+    }
+
+    #[test]
+    fn ssh_urls_with_user_and_port() {
+        let url = parse_ssh_url("ssh://git@example.com:2222/foo/bar.git");
+        assert_eq!(url, Some(SshUrl {
+            user: Some("git".to_string()),
+            host: "example.com".to_string(),
+            port: Some(2222),
+            path: "/foo/bar.git".to_string(),
+        }));
+        assert_eq!(url.unwrap().to_ssh_url().as_slice(),
+                   "ssh://git@example.com:2222/foo/bar.git");
+
+        let url = parse_ssh_url("ssh://example.com/foo/bar.git").unwrap();
+        assert_eq!(url.user, None);
+        assert_eq!(url.port, None);
+        assert!(parse_ssh_url("ssh://example.com:notaport/foo").is_none());
+    }
+
+    #[test]
+    fn scp_like_ssh_urls() {
+        let url = parse_ssh_url("git@example.com:foo/bar.git");
+        assert_eq!(url, Some(SshUrl {
+            user: Some("git".to_string()),
+            host: "example.com".to_string(),
+            port: None,
+            path: "foo/bar.git".to_string(),
+        }));
+        assert_eq!(url.unwrap().to_ssh_url().as_slice(),
+                   "ssh://git@example.com/foo/bar.git");
+
+        let url = parse_ssh_url("example.com:/srv/bar.git").unwrap();
+        assert_eq!(url.user, None);
+        assert_eq!(url.path.as_slice(), "/srv/bar.git");
+    }
+
+    #[test]
+    fn both_ssh_url_forms_agree() {
+        let scp = parse_ssh_url("alice@example.com:bar.git").unwrap();
+        let ssh = parse_ssh_url("ssh://alice@example.com/bar.git").unwrap();
+        assert_eq!(scp.user, ssh.user);
+        assert_eq!(scp.host, ssh.host);
+        assert_eq!(scp.to_ssh_url(), ssh.to_ssh_url());
+    }
+
+    #[test]
+    fn non_ssh_urls() {
+        assert!(parse_ssh_url("https://example.com/foo/bar.git").is_none());
+        assert!(parse_ssh_url("file:///foo/bar").is_none());
+        assert!(parse_ssh_url("./foo:bar").is_none());
+        assert!(parse_ssh_url("C:\\foo\\bar").is_none());
+    }
+
+    #[test]
+    fn short_revisions() {
+        let id = "0123456789abcdef0123456789abcdef01234567";
+        let rev = GitRevision(git2::Oid::from_str(id).unwrap());
+        assert_eq!(rev.short().as_slice(), "0123456");
+    }
+
+    #[test]
+    fn revisions_round_trip() {
+        let id = "0123456789abcdef0123456789abcdef01234567";
+        let rev: GitRevision = from_str(id).unwrap();
+        let encoded = json::encode(&rev);
+        assert_eq!(encoded.as_slice(), format!("\"{}\"", id).as_slice());
+        let decoded: GitRevision = json::decode(encoded.as_slice()).unwrap();
+        assert_eq!(decoded, rev);
+    }
+
+    #[test]
+    fn malformed_revisions() {
+        assert!(from_str::<GitRevision>("").is_none());
+        assert!(from_str::<GitRevision>("0123456").is_none());
+        assert!(from_str::<GitRevision>(
+            "0123456789abcdef0123456789abcdef0123456z").is_none());
+        assert!(from_str::<GitRevision>(
+            "0123456789abcdef0123456789abcdef012345678").is_none());
+    }
+
+    #[test]
+    fn remotes_are_the_same_whichever_way_their_url_is_spelled() {
+        let a = GitRemote::new(&"https://github.com/foo/bar".to_url().unwrap());
+        let b = GitRemote::new(&"https://github.com/Foo/bar.git/".to_url().unwrap());
+        let c = GitRemote::new(&"https://github.com/foo/baz".to_url().unwrap());
+        assert!(a == b);
+        assert_eq!(hash::hash(&a), hash::hash(&b));
+        assert!(a != c);
+    }
+
+    #[test]
+    fn credential_helpers_are_run_like_git() {
+        let helper = "!echo protocol=https; echo username=bob; \
+                      echo password=hunter2; :";
+        assert_eq!(run_credential_helper(helper, "https://example.com/foo",
+                                         None),
+                   Some(("bob".to_string(), "hunter2".to_string())));
+
+        // The username being asked for is kept if the helper doesn't say
+        let helper = "!echo password=hunter2; :";
+        assert_eq!(run_credential_helper(helper, "https://example.com/foo",
+                                         Some("alice")),
+                   Some(("alice".to_string(), "hunter2".to_string())));
+
+        // Anything else isn't run through the shell
+        assert_eq!(run_credential_helper("/usr/bin/printf password=hunter2\\n",
+                                         "https://example.com", Some("alice")),
+                   Some(("alice".to_string(), "hunter2".to_string())));
+
+        assert_eq!(run_credential_helper("!exit 1; :", "https://example.com",
+                                         Some("alice")), None);
+        assert_eq!(run_credential_helper("!echo username=bob; :",
+                                         "https://example.com", None), None);
+    }
+
+    #[test]
+    fn credential_urls_match_by_scheme_host_and_path() {
+        let url = Url::parse("https://example.com/foo/bar.git").unwrap();
+        assert!(credential_url_matches("https://example.com", &url));
+        assert!(credential_url_matches("https://example.com/", &url));
+        assert!(credential_url_matches("https://example.com/foo", &url));
+        assert!(credential_url_matches("https://example.com/foo/bar.git", &url));
+        assert!(!credential_url_matches("https://example.com/fo", &url));
+        assert!(!credential_url_matches("https://example.com/foo/baz", &url));
+        assert!(!credential_url_matches("http://example.com", &url));
+        assert!(!credential_url_matches("https://example.com.evil.org", &url));
+        assert!(!credential_url_matches("https://example.com:8443", &url));
+    }
+
+    #[test]
+    fn signing_keys_have_to_be_long() {
+        assert_eq!(signing_key("0123 4567 89ab cdef").unwrap().as_slice(),
+                   "0123456789ABCDEF");
+        assert!(signing_key("89ABCDEF").is_err());
+        assert!(signing_key("0x0123456789ABCDEF").is_err());
+    }
+
+    #[test]
+    fn repository_locks_are_exclusive_and_released() {
+        let dir = TempDir::new("cargo-lock").unwrap();
+        let repo = dir.path().join("repo");
+        let file = dir.path().join("repo.lock");
+        {
+            let _lock = RepositoryLock::acquire(&repo).unwrap();
+            let other = FileLock::open(&file).unwrap();
+            assert!(!other.try_lock().unwrap());
+        }
+        assert!(!file.exists());
+        let _lock = RepositoryLock::acquire(&repo).unwrap();
+    }
+
+    #[test]
+    fn running_out_of_disk_space_is_recognised() {
+        // Writes to /dev/full always fail as if the disk were full
+        if cfg!(target_os = "linux") {
+            assert!(is_disk_full(&Path::new("/dev/full")));
+        }
+        let dir = TempDir::new("cargo-disk").unwrap();
+        assert!(!is_disk_full(&dir.path().join("probe")));
+        assert!(!is_disk_full(&dir.path().join("missing/probe")));
+    }
+
+    #[test]
+    fn default_branch_is_the_target_of_the_head_symref() {
+        let listing = "ref: refs/heads/trunk\tHEAD\n\
+                       0123456789abcdef0123456789abcdef01234567\tHEAD\n";
+        assert_eq!(head_branch(listing), Some("trunk".to_string()));
+        let detached = "0123456789abcdef0123456789abcdef01234567\tHEAD\n";
+        assert_eq!(head_branch(detached), None);
+    }
+
+    #[test]
+    fn config_options_are_kept_off_the_command_line() {
+        let options = [("http.extraHeader".to_string(),
+                        "Authorization: Bearer secret".to_string())];
+        let cmd = git_with_options(&options).unwrap();
+        assert!(!cmd.to_string().as_slice().contains("secret"));
+        assert_eq!(cmd.get_args().len(), 0);
+    }
+
+    #[test]
+    fn http_statuses_are_found_in_messages() {
+        assert_eq!(http_status("Unexpected HTTP status code: 404", "status code: "),
+                   Some(404));
+        assert_eq!(http_status("fatal: unable to access 'https://example.com/': \
+                                The requested URL returned error: 403",
+                               "returned error: "), Some(403));
+        assert_eq!(http_status("failed to resolve address", "status code: "), None);
+    }
+
+    #[test]
+    fn repositories_are_put_back_if_their_replacement_cant_be_moved() {
+        let dir = TempDir::new("cargo-staging").unwrap();
+        let dst = dir.path().join("repo");
+        fs::mkdir(&dst, USER_DIR).unwrap();
+        File::create(&dst.join("HEAD")).write_str("ref: refs/heads/master").unwrap();
+
+        let staging = dir.path().join("staging");
+        fs::mkdir(&staging, USER_DIR).unwrap();
+        let missing = staging.join("repo");
+        assert!(move_into_place(&staging, &missing, &dst).is_err());
+        assert!(dst.join("HEAD").exists());
+        assert!(!staging.join("old").exists());
+    }
+
+    #[test]
+    fn received_objects_are_found_in_git_progress() {
+        let stderr = "remote: Counting objects: 3, done.\n\
+                      Receiving objects:  33% (1/3)\r\
+                      Receiving objects: 100% (3/3), 1.20 KiB | 0 bytes/s, done.\n";
+        assert_eq!(received_objects(stderr), Some(3));
+        assert_eq!(received_objects("From /tmp/bar\n"), None);
+    }
+
+    #[test]
+    fn http_tuning_is_only_looked_at_for_http_urls() {
+        let dir = TempDir::new("cargo-config").unwrap();
+        let path = dir.path().join("config");
+        File::create(&path).write_str("").unwrap();
+        let mut cfg = git2::Config::open(&path).unwrap();
+        assert_eq!(http_tuning(&cfg, "https://example.com/foo"), None);
+
+        cfg.set_i64("http.lowSpeedTime", 60).unwrap();
+        assert_eq!(http_tuning(&cfg, "https://example.com/foo"),
+                   Some("http.lowSpeedTime"));
+        cfg.set_i64("http.postBuffer", 524288000).unwrap();
+        assert_eq!(http_tuning(&cfg, "http://example.com/foo"),
+                   Some("http.postBuffer"));
+        assert_eq!(http_tuning(&cfg, "ssh://git@example.com/foo"), None);
+    }
+
+    #[test]
+    fn hosts_excluded_from_proxies() {
+        assert!(no_proxy_matches("example.com", "example.com"));
+        assert!(no_proxy_matches("localhost, example.com", "git.example.com"));
+        assert!(no_proxy_matches(".example.com", "git.Example.com"));
+        assert!(no_proxy_matches("*", "example.org"));
+        assert!(!no_proxy_matches("example.com", "badexample.com"));
+        assert!(!no_proxy_matches("git.example.com", "example.com"));
+        assert!(!no_proxy_matches("", "example.com"));
+    }
+
+    #[test]
+    fn hmac_sha1_of_known_vectors() {
+        // From RFC 2202
+        assert_eq!(to_hex(hmac_sha1(&[0x0b, ..20], b"Hi There").as_slice()).as_slice(),
+                   "b617318655057264e28bc0b6fb378c8ef146be00");
+        assert_eq!(to_hex(hmac_sha1(b"Jefe", b"what do ya want for nothing?")
+                              .as_slice()).as_slice(),
+                   "effcdab6e9dc02a6b8d8d4d3fdf4da0e7f7b9cb0");
+    }
+
+    #[test]
+    fn host_keys_are_looked_up_in_known_hosts() {
+        let key = b"some host key".as_slice().to_base64(STANDARD);
+        let other = b"another host key".as_slice().to_base64(STANDARD);
+        let mut sha1 = Sha1::new();
+        sha1.update(b"some host key");
+        let fingerprint = sha1.finish();
+        let status = |known_hosts: &str, host: &str| {
+            host_key_status(known_hosts, host, fingerprint.as_slice())
+        };
+
+        let known_hosts = format!("# a comment\n\
+                                   example.com,192.0.2.1 ssh-rsa {key}\n\
+                                   [example.com]:2222 ssh-rsa {other}\n\
+                                   *.example.org,!evil.example.org ssh-rsa {key}\n\
+                                   @revoked revoked.example.com ssh-rsa {key}\n\
+                                   revoked.example.com ssh-rsa {key}\n",
+                                  key = key, other = other);
+        let known_hosts = known_hosts.as_slice();
+        assert_eq!(status(known_hosts, "example.com"), HostKeyStatus::Known);
+        assert_eq!(status(known_hosts, "EXAMPLE.com"), HostKeyStatus::Known);
+        assert_eq!(status(known_hosts, "[example.com]:2222"), HostKeyStatus::Changed);
+        assert_eq!(status(known_hosts, "git.example.org"), HostKeyStatus::Known);
+        assert_eq!(status(known_hosts, "evil.example.org"), HostKeyStatus::Unknown);
+        assert_eq!(status(known_hosts, "revoked.example.com"), HostKeyStatus::Revoked);
+        assert_eq!(status(known_hosts, "example.net"), HostKeyStatus::Unknown);
+
+        // Hashed entries are `|1|salt|hash` where the hash is the HMAC of the
+        // host's name
+        let salt = b"01234567890123456789";
+        let hashed = format!("|1|{}|{} ssh-rsa {}", salt.as_slice().to_base64(STANDARD),
+                             hmac_sha1(salt, b"example.com").as_slice()
+                                                             .to_base64(STANDARD),
+                             key);
+        assert_eq!(status(hashed.as_slice(), "example.com"), HostKeyStatus::Known);
+        assert_eq!(status(hashed.as_slice(), "example.net"), HostKeyStatus::Unknown);
+    }
+}
@@ -442,7 +442,13 @@ impl<'a, 'b> RegistrySource<'a, 'b> {
         // git fetch origin
         let url = self.source_id.get_url().to_string();
         let refspec = "refs/heads/*:refs/remotes/origin/*";
-        try!(git::fetch(&repo, url.as_slice(), refspec).chain_error(|| {
+        git::show_progress(Some(self.config.shell().err().get_config()));
+        let ret = git::fetch(&repo, url.as_slice(), refspec);
+        git::show_progress(None);
+        for warning in git::take_warnings().into_iter() {
+            try!(self.config.shell().warn(warning));
+        }
+        try!(ret.chain_error(|| {
             internal(format!("failed to fetch `{}`", url))
         }));
 
@@ -106,6 +106,7 @@ pub enum ConfigValue {
     List(Vec<(string::String, Path)>),
     Table(HashMap<string::String, ConfigValue>),
     Boolean(bool, Path),
+    Integer(i64, Path),
 }
 
 impl fmt::Show for ConfigValue {
@@ -126,6 +127,9 @@ impl fmt::Show for ConfigValue {
             CV::Boolean(b, ref path) => {
                 write!(f, "{} (from {})", b, path.display())
             }
+            CV::Integer(i, ref path) => {
+                write!(f, "{} (from {})", i, path.display())
+            }
         }
     }
 }
@@ -140,6 +144,7 @@ impl<E, S: Encoder<E>> Encodable<S, E> for ConfigValue {
             }
             CV::Table(ref table) => table.encode(s),
             CV::Boolean(b, _) => b.encode(s),
+            CV::Integer(i, _) => i.encode(s),
         }
     }
 }
@@ -149,6 +154,7 @@ impl ConfigValue {
         match toml {
             toml::Value::String(val) => Ok(CV::String(val, path.clone())),
             toml::Value::Boolean(b) => Ok(CV::Boolean(b, path.clone())),
+            toml::Value::Integer(i) => Ok(CV::Integer(i, path.clone())),
             toml::Value::Array(val) => {
                 Ok(CV::List(try!(val.into_iter().map(|toml| {
                     match toml {
@@ -170,7 +176,8 @@ impl ConfigValue {
     fn merge(&mut self, from: ConfigValue) -> CargoResult<()> {
         match (self, from) {
             (&CV::String(..), CV::String(..)) |
-            (&CV::Boolean(..), CV::Boolean(..)) => {}
+            (&CV::Boolean(..), CV::Boolean(..)) |
+            (&CV::Integer(..), CV::Integer(..)) => {}
             (&CV::List(ref mut old), CV::List(ref mut new)) => {
                 let new = mem::replace(new, Vec::new());
                 old.extend(new.into_iter());
@@ -225,18 +232,28 @@ impl ConfigValue {
         }
     }
 
+    pub fn integer(&self) -> CargoResult<(i64, &Path)> {
+        match *self {
+            CV::Integer(i, ref p) => Ok((i, p)),
+            _ => Err(internal(format!("expected an integer, but found a {}",
+                                      self.desc()))),
+        }
+    }
+
     pub fn desc(&self) -> &'static str {
         match *self {
             CV::Table(..) => "table",
             CV::List(..) => "array",
             CV::String(..) => "string",
             CV::Boolean(..) => "boolean",
+            CV::Integer(..) => "integer",
         }
     }
 
     fn into_toml(self) -> toml::Value {
         match self {
             CV::Boolean(s, _) => toml::Value::Boolean(s),
+            CV::Integer(i, _) => toml::Value::Integer(i),
             CV::String(s, _) => toml::Value::String(s),
             CV::List(l) => toml::Value::Array(l
                                         .into_iter()
@@ -0,0 +1,212 @@
+#![allow(bad_style)]
+
+pub use self::imp::FileLock;
+
+// An exclusive advisory lock on a file, which the OS releases by itself should
+// the process holding it die. The file is removed again when the lock is
+// released, so whoever takes a lock has to check with `is_at` that the file
+// they locked wasn't removed under them by the previous holder.
+#[cfg(unix)]
+mod imp {
+    use std::cell::Cell;
+    use std::io::{IoResult, IoError};
+    use std::io::fs;
+    use std::mem;
+    use std::os;
+    use libc;
+
+    static LOCK_EX: libc::c_int = 2;
+    static LOCK_NB: libc::c_int = 4;
+
+    extern {
+        fn flock(fd: libc::c_int, operation: libc::c_int) -> libc::c_int;
+    }
+
+    pub struct FileLock {
+        fd: libc::c_int,
+        path: Path,
+        locked: Cell<bool>,
+    }
+
+    impl FileLock {
+        /// Opens (creating it if needed) the file at `path`, without locking
+        /// it yet.
+        pub fn open(path: &Path) -> IoResult<FileLock> {
+            let fd = unsafe {
+                let path = path.to_c_str();
+                libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CREAT, 0o644)
+            };
+            if fd < 0 {
+                return Err(IoError::last_error())
+            }
+            Ok(FileLock { fd: fd, path: path.clone(), locked: Cell::new(false) })
+        }
+
+        /// Takes the lock unless another process holds it, returning whether
+        /// it was taken.
+        pub fn try_lock(&self) -> IoResult<bool> {
+            if unsafe { flock(self.fd, LOCK_EX | LOCK_NB) } == 0 {
+                self.locked.set(true);
+                return Ok(true)
+            }
+            if os::errno() as libc::c_int == libc::EWOULDBLOCK {
+                Ok(false)
+            } else {
+                Err(IoError::last_error())
+            }
+        }
+
+        /// Takes the lock, waiting for as long as another process holds it.
+        pub fn lock(&self) -> IoResult<()> {
+            if unsafe { flock(self.fd, LOCK_EX) } == 0 {
+                self.locked.set(true);
+                Ok(())
+            } else {
+                Err(IoError::last_error())
+            }
+        }
+
+        /// Returns whether the locked file is still the one at its path.
+        pub fn is_at(&self) -> bool {
+            unsafe {
+                let mut ours: libc::stat = mem::zeroed();
+                let mut theirs: libc::stat = mem::zeroed();
+                let path = self.path.to_c_str();
+                libc::fstat(self.fd, &mut ours) == 0 &&
+                    libc::stat(path.as_ptr(), &mut theirs) == 0 &&
+                    ours.st_dev == theirs.st_dev && ours.st_ino == theirs.st_ino
+            }
+        }
+    }
+
+    impl Drop for FileLock {
+        fn drop(&mut self) {
+            // Removing the file while the lock is still held means nobody can
+            // take the lock on it in between.
+            if self.locked.get() {
+                let _ = fs::unlink(&self.path);
+            }
+            unsafe { libc::close(self.fd); }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::cell::Cell;
+    use std::io::{IoResult, IoError};
+    use std::io::fs;
+    use std::mem;
+    use std::os;
+    use std::ptr;
+    use libc::{DWORD, BOOL, HANDLE, LPCWSTR};
+
+    static GENERIC_READ: DWORD = 0x80000000;
+    static GENERIC_WRITE: DWORD = 0x40000000;
+    static FILE_SHARE_READ: DWORD = 0x1;
+    static FILE_SHARE_WRITE: DWORD = 0x2;
+    static OPEN_ALWAYS: DWORD = 4;
+    static FILE_ATTRIBUTE_NORMAL: DWORD = 0x80;
+    static LOCKFILE_FAIL_IMMEDIATELY: DWORD = 0x1;
+    static LOCKFILE_EXCLUSIVE_LOCK: DWORD = 0x2;
+    static ERROR_LOCK_VIOLATION: uint = 33;
+    static INVALID_HANDLE_VALUE: HANDLE = -1 as HANDLE;
+
+    #[repr(C)]
+    struct OVERLAPPED {
+        Internal: uint,
+        InternalHigh: uint,
+        Offset: DWORD,
+        OffsetHigh: DWORD,
+        hEvent: HANDLE,
+    }
+
+    extern "system" {
+        fn CreateFileW(lpFileName: LPCWSTR, dwDesiredAccess: DWORD,
+                       dwShareMode: DWORD, lpSecurityAttributes: *mut u8,
+                       dwCreationDisposition: DWORD,
+                       dwFlagsAndAttributes: DWORD,
+                       hTemplateFile: HANDLE) -> HANDLE;
+        fn LockFileEx(hFile: HANDLE, dwFlags: DWORD, dwReserved: DWORD,
+                      nNumberOfBytesToLockLow: DWORD,
+                      nNumberOfBytesToLockHigh: DWORD,
+                      lpOverlapped: *mut OVERLAPPED) -> BOOL;
+        fn CloseHandle(hObject: HANDLE) -> BOOL;
+    }
+
+    pub struct FileLock {
+        handle: HANDLE,
+        path: Path,
+        locked: Cell<bool>,
+    }
+
+    impl FileLock {
+        /// Opens (creating it if needed) the file at `path`, without locking
+        /// it yet.
+        pub fn open(path: &Path) -> IoResult<FileLock> {
+            let mut name = path.as_str().unwrap_or("").utf16_units()
+                               .collect::<Vec<u16>>();
+            name.push(0);
+            // The file isn't opened for deletion by others, so it can't be
+            // removed while anyone has it open.
+            let handle = unsafe {
+                CreateFileW(name.as_ptr(), GENERIC_READ | GENERIC_WRITE,
+                            FILE_SHARE_READ | FILE_SHARE_WRITE, ptr::null_mut(),
+                            OPEN_ALWAYS, FILE_ATTRIBUTE_NORMAL, ptr::null_mut())
+            };
+            if handle == INVALID_HANDLE_VALUE {
+                return Err(IoError::last_error())
+            }
+            Ok(FileLock { handle: handle, path: path.clone(), locked: Cell::new(false) })
+        }
+
+        /// Takes the lock unless another process holds it, returning whether
+        /// it was taken.
+        pub fn try_lock(&self) -> IoResult<bool> {
+            if self.lock_file(LOCKFILE_FAIL_IMMEDIATELY) {
+                self.locked.set(true);
+                return Ok(true)
+            }
+            if os::errno() == ERROR_LOCK_VIOLATION {
+                Ok(false)
+            } else {
+                Err(IoError::last_error())
+            }
+        }
+
+        /// Takes the lock, waiting for as long as another process holds it.
+        pub fn lock(&self) -> IoResult<()> {
+            if self.lock_file(0) {
+                self.locked.set(true);
+                Ok(())
+            } else {
+                Err(IoError::last_error())
+            }
+        }
+
+        /// Returns whether the locked file is still the one at its path, which
+        /// it always is as it can't be removed while it's open.
+        pub fn is_at(&self) -> bool {
+            true
+        }
+
+        fn lock_file(&self, flags: DWORD) -> bool {
+            unsafe {
+                let mut overlapped: OVERLAPPED = mem::zeroed();
+                LockFileEx(self.handle, LOCKFILE_EXCLUSIVE_LOCK | flags, 0,
+                           !0, !0, &mut overlapped) != 0
+            }
+        }
+    }
+
+    impl Drop for FileLock {
+        fn drop(&mut self) {
+            // Removing the file only works once nobody has it open anymore,
+            // so nobody can be waiting for the lock on it either.
+            unsafe { CloseHandle(self.handle); }
+            if self.locked.get() {
+                let _ = fs::unlink(&self.path);
+            }
+        }
+    }
+}
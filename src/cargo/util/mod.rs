@@ -13,7 +13,9 @@ pub use self::graph::Graph;
 pub use self::to_url::ToUrl;
 pub use self::to_semver::ToSemver;
 pub use self::vcs::{GitRepo, HgRepo};
+pub use self::sha1::Sha1;
 pub use self::sha256::Sha256;
+pub use self::flock::FileLock;
 
 pub mod config;
 pub mod errors;
@@ -29,5 +31,7 @@ pub mod to_url;
 pub mod toml;
 pub mod lev_distance;
 mod dependency_queue;
+mod flock;
+mod sha1;
 mod sha256;
 mod vcs;
@@ -15,6 +15,7 @@ use core::{Summary, Manifest, Target, Dependency, PackageId, GitReference};
 use core::dependency::Kind;
 use core::manifest::{LibKind, Profile, ManifestMetadata};
 use core::package_id::Metadata;
+use sources::git;
 use util::{CargoResult, Require, human, ToUrl, ToSemver};
 
 /// Representation of the projects file layout.
@@ -304,7 +305,11 @@ impl TomlProject {
 struct Context<'a> {
     deps: &'a mut Vec<Dependency>,
     source_id: &'a SourceId,
-    nested_paths: &'a mut Vec<Path>
+    nested_paths: &'a mut Vec<Path>,
+    // The branch of `git.default-branch` (or `master`), only read once a git
+    // dependency needs it. Git sources may still pick the remote's `HEAD`
+    // branch instead if it isn't configured.
+    default_branch: Option<String>,
 }
 
 // These functions produce the equivalent of specific manifest entries. One
@@ -485,7 +490,8 @@ impl TomlManifest {
             let mut cx = Context {
                 deps: &mut deps,
                 source_id: source_id,
-                nested_paths: &mut nested_paths
+                nested_paths: &mut nested_paths,
+                default_branch: None,
             };
 
             // Collect the deps
@@ -554,6 +560,16 @@ impl TomlManifest {
     }
 }
 
+fn default_branch(cx: &mut Context) -> CargoResult<String> {
+    if cx.default_branch.is_none() {
+        let config = try!(git::git_configuration());
+        cx.default_branch = Some(config.default_branch.unwrap_or_else(|| {
+            "master".to_string()
+        }));
+    }
+    Ok(cx.default_branch.clone().unwrap())
+}
+
 fn process_dependencies<'a>(cx: &mut Context<'a>,
                             new_deps: Option<&HashMap<String, TomlDependency>>,
                             f: |Dependency| -> Dependency)
@@ -573,15 +589,22 @@ fn process_dependencies<'a>(cx: &mut Context<'a>,
         };
         let reference = details.branch.clone().map(GitReference::Branch)
             .or_else(|| details.tag.clone().map(GitReference::Tag))
-            .or_else(|| details.rev.clone().map(GitReference::Rev))
-            .unwrap_or_else(|| GitReference::Branch("master".to_string()));
+            .or_else(|| details.rev.clone().map(GitReference::Rev));
 
         let new_source_id = match details.git {
             Some(ref git) => {
                 let loc = try!(git.as_slice().to_url().map_err(|e| {
                     human(e)
                 }));
-                Some(SourceId::for_git(&loc, reference))
+                match reference {
+                    Some(reference) => Some(SourceId::for_git(&loc, reference)),
+                    None => {
+                        let branch = GitReference::Branch(try!(default_branch(cx)));
+                        let id = SourceId::for_git(&loc, branch);
+                        git::record_default_branch(&id);
+                        Some(id)
+                    }
+                }
             }
             None => {
                 details.path.as_ref().map(|path| {
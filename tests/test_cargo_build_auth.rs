@@ -4,7 +4,7 @@ use std::io::{TcpListener, Listener, Acceptor, BufferedStream};
 use std::thread::Thread;
 use git2;
 
-use support::{project, execs, ResultTest, UPDATING};
+use support::{project, execs, ResultTest, CLONING};
 use support::paths;
 use hamcrest::assert_that;
 
@@ -104,16 +104,19 @@ test!(http_auth_offered {
 
     assert_that(p.cargo_process("build").arg("-v"),
                 execs().with_status(101).with_stdout(format!("\
-{updating} git repository `http://{addr}/foo/bar`
+{cloning} git repository `http://{addr}/foo/bar`
 ",
-        updating = UPDATING,
+        cloning = CLONING,
         addr = addr,
         ).as_slice())
                       .with_stderr(format!("\
 Unable to update http://{addr}/foo/bar
 
 Caused by:
-  failed to clone into: [..]
+  failed to clone `[..]` into [..]
+
+Caused by:
+  access denied to `http://{addr}/foo/bar`; check your credentials
 
 Caused by:
   [12] [..] status code: 401
@@ -148,16 +151,16 @@ test!(https_something_happens {
 
     assert_that(p.cargo_process("build").arg("-v"),
                 execs().with_status(101).with_stdout(format!("\
-{updating} git repository `https://{addr}/foo/bar`
+{cloning} git repository `https://{addr}/foo/bar`
 ",
-        updating = UPDATING,
+        cloning = CLONING,
         addr = addr,
         ).as_slice())
                       .with_stderr(format!("\
 Unable to update https://{addr}/foo/bar
 
 Caused by:
-  failed to clone into: [..]
+  failed to clone `[..]` into [..]
 
 Caused by:
   [[..]] {errmsg}
@@ -198,16 +201,16 @@ test!(ssh_something_happens {
 
     assert_that(p.cargo_process("build").arg("-v"),
                 execs().with_status(101).with_stdout(format!("\
-{updating} git repository `ssh://{addr}/foo/bar`
+{cloning} git repository `ssh://{addr}/foo/bar`
 ",
-        updating = UPDATING,
+        cloning = CLONING,
         addr = addr,
         ).as_slice())
                       .with_stderr(format!("\
 Unable to update ssh://{addr}/foo/bar
 
 Caused by:
-  failed to clone into: [..]
+  failed to clone `[..]` into [..]
 
 Caused by:
   [23] Failed to start SSH session: Failed getting banner
@@ -1,15 +1,21 @@
-use std::io::fs;
-use std::io::{timer, File};
+use std::io::fs::{mod, PathExtensions};
+use std::io::net::tcp::TcpAcceptor;
+use std::io::{timer, File, USER_DIR, USER_RWX, USER_EXECUTE};
+use std::io::{TcpListener, Listener, Acceptor, BufferedStream};
+use std::thread::Thread;
 use std::time::Duration;
 use git2;
 
 use support::{ProjectBuilder, ResultTest, project, execs, main_file};
 use support::{cargo_dir, path2url};
-use support::{COMPILING, UPDATING, RUNNING};
-use support::paths::PathExt;
+use support::{COMPILING, UPDATING, RUNNING, CLONING};
+use support::paths::{mod, PathExt};
 use hamcrest::{assert_that,existing_file};
 use cargo;
-use cargo::util::{ProcessError, process};
+use cargo::core::GitReference;
+use cargo::sources::git::{GitRemote, GitCheckout, GitRevision, GitErrorKind};
+use cargo::sources::git::{CredentialProvider, ChangeKind, fetch_with_credentials};
+use cargo::util::{CargoError, ProcessError, process};
 
 
 fn setup() {
@@ -122,7 +128,7 @@ test!(cargo_compile_simple_git_dep {
         .with_stdout(format!("{} git repository `{}`\n\
                               {} dep1 v0.5.0 ({}#[..])\n\
                               {} foo v0.5.0 ({})\n",
-                             UPDATING, path2url(git_root.clone()),
+                             CLONING, path2url(git_root.clone()),
                              COMPILING, path2url(git_root),
                              COMPILING, path2url(root)))
         .with_stderr(""));
@@ -189,7 +195,7 @@ test!(cargo_compile_git_dep_branch {
         .with_stdout(format!("{} git repository `{}`\n\
                               {} dep1 v0.5.0 ({}?branch=branchy#[..])\n\
                               {} foo v0.5.0 ({})\n",
-                             UPDATING, path2url(git_root.clone()),
+                             CLONING, path2url(git_root.clone()),
                              COMPILING, path2url(git_root),
                              COMPILING, path2url(root)))
         .with_stderr(""));
@@ -259,7 +265,7 @@ test!(cargo_compile_git_dep_tag {
         .with_stdout(format!("{} git repository `{}`\n\
                               {} dep1 v0.5.0 ({}?tag=v0.1.0#[..])\n\
                               {} foo v0.5.0 ({})\n",
-                             UPDATING, path2url(git_root.clone()),
+                             CLONING, path2url(git_root.clone()),
                              COMPILING, path2url(git_root),
                              COMPILING, path2url(root))));
 
@@ -560,7 +566,7 @@ test!(recompilation {
                 execs().with_stdout(format!("{} git repository `{}`\n\
                                              {} bar v0.5.0 ({}#[..])\n\
                                              {} foo v0.5.0 ({})\n",
-                                            UPDATING, git_project.url(),
+                                            CLONING, git_project.url(),
                                             COMPILING, git_project.url(),
                                             COMPILING, p.url())));
 
@@ -677,12 +683,12 @@ test!(update_with_shared_deps {
     // First time around we should compile both foo and bar
     assert_that(p.cargo_process("build"),
                 execs().with_stdout(format!("\
-{updating} git repository `{git}`
+{cloning} git repository `{git}`
 {compiling} bar v0.5.0 ({git}#[..])
 {compiling} [..] v0.5.0 ({dir})
 {compiling} [..] v0.5.0 ({dir})
 {compiling} foo v0.5.0 ({dir})\n",
-                    updating = UPDATING, git = git_project.url(),
+                    cloning = CLONING, git = git_project.url(),
                     compiling = COMPILING, dir = p.url())));
 
     // Modify a file manually, and commit it
@@ -821,8 +827,8 @@ test!(two_deps_only_update_one {
                               {} [..] v0.5.0 ([..])\n\
                               {} [..] v0.5.0 ([..])\n\
                               {} foo v0.5.0 ({})\n",
-                             UPDATING,
-                             UPDATING,
+                             CLONING,
+                             CLONING,
                              COMPILING,
                              COMPILING,
                              COMPILING, project.url()))
@@ -962,7 +968,7 @@ test!(dep_with_changed_submodule {
                                       {} `target[..]foo`\n\
                                       project2\
                                       ",
-                                      UPDATING,
+                                      CLONING,
                                       COMPILING,
                                       COMPILING,
                                       RUNNING))
@@ -1051,9 +1057,9 @@ test!(dev_deps_with_testing {
     // `bar` to generate the lockfile
     assert_that(p.cargo_process("build"),
         execs().with_stdout(format!("\
-{updating} git repository `{bar}`
+{cloning} git repository `{bar}`
 {compiling} foo v0.5.0 ({url})
-", updating = UPDATING, compiling = COMPILING, url = p.url(), bar = p2.url())));
+", cloning = CLONING, compiling = COMPILING, url = p.url(), bar = p2.url())));
 
     // Make sure we use the previous resolution of `bar` instead of updating it
     // a second time.
@@ -1143,9 +1149,9 @@ test!(git_name_not_always_needed {
     // `bar` to generate the lockfile
     assert_that(p.cargo_process("build"),
         execs().with_stdout(format!("\
-{updating} git repository `{bar}`
+{cloning} git repository `{bar}`
 {compiling} foo v0.5.0 ({url})
-", updating = UPDATING, compiling = COMPILING, url = p.url(), bar = p2.url())));
+", cloning = CLONING, compiling = COMPILING, url = p.url(), bar = p2.url())));
 });
 
 test!(git_repo_changing_no_rebuild {
@@ -1175,10 +1181,10 @@ test!(git_repo_changing_no_rebuild {
     p1.root().move_into_the_past().assert();
     assert_that(p1.process(cargo_dir().join("cargo")).arg("build"),
                 execs().with_stdout(format!("\
-{updating} git repository `{bar}`
+{cloning} git repository `{bar}`
 {compiling} [..]
 {compiling} [..]
-", updating = UPDATING, compiling = COMPILING, bar = bar.url())));
+", cloning = CLONING, compiling = COMPILING, bar = bar.url())));
 
     // Make a commit to lock p2 to a different rev
     File::create(&bar.root().join("src/lib.rs")).write_str(r#"
@@ -1294,8 +1300,8 @@ test!(fetch_downloads {
         .file("src/main.rs", "fn main() {}");
     assert_that(p.cargo_process("fetch"),
                 execs().with_status(0).with_stdout(format!("\
-{updating} git repository `{url}`
-", updating = UPDATING, url = bar.url())));
+{cloning} git repository `{url}`
+", cloning = CLONING, url = bar.url())));
 
     assert_that(p.process(cargo_dir().join("cargo")).arg("fetch"),
                 execs().with_status(0).with_stdout(""));
@@ -1328,7 +1334,7 @@ test!(warnings_in_git_dep {
         .with_stdout(format!("{} git repository `{}`\n\
                               {} bar v0.5.0 ({}#[..])\n\
                               {} foo v0.5.0 ({})\n",
-                             UPDATING, bar.url(),
+                             CLONING, bar.url(),
                              COMPILING, bar.url(),
                              COMPILING, p.url()))
         .with_stderr(""));
@@ -1609,11 +1615,11 @@ test!(switch_sources {
     assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
                 execs().with_status(0)
                        .with_stdout(format!("\
-{updating} git repository `file://[..]a1`
+{cloning} git repository `file://[..]a1`
 {compiling} a v0.5.0 ([..]a1#[..]
 {compiling} b v0.5.0 ([..])
 {compiling} project v0.5.0 ([..])
-", updating = UPDATING, compiling = COMPILING).as_slice()));
+", cloning = CLONING, compiling = COMPILING).as_slice()));
 
     File::create(&p.root().join("b/Cargo.toml")).write_str(format!(r#"
         [project]
@@ -1627,9 +1633,1479 @@ test!(switch_sources {
     assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
                 execs().with_status(0)
                        .with_stdout(format!("\
-{updating} git repository `file://[..]a2`
+{cloning} git repository `file://[..]a2`
 {compiling} a v0.5.1 ([..]a2#[..]
 {compiling} b v0.5.0 ([..])
 {compiling} project v0.5.0 ([..])
-", updating = UPDATING, compiling = COMPILING).as_slice()));
+", cloning = CLONING, compiling = COMPILING).as_slice()));
+});
+
+test!(unsupported_url_scheme {
+    let p = project("foo")
+        .file("Cargo.toml", r#"
+            [project]
+            name = "foo"
+            version = "0.0.1"
+            authors = []
+
+            [dependencies.bar]
+            git = "htttps://example.com/foo/bar"
+        "#)
+        .file("src/main.rs", "fn main() {}");
+
+    assert_that(p.cargo_process("build"),
+                execs().with_status(101)
+                       .with_stdout(format!("\
+{cloning} git repository `htttps://example.com/foo/bar`
+", cloning = CLONING).as_slice())
+                       .with_stderr("\
+Unable to update htttps://example.com/foo/bar
+
+Caused by:
+  unsupported url scheme `htttps` in git repository `htttps://example.com/foo/bar`
+supported schemes are http, https, ssh, git, file
+"));
+});
+
+test!(warn_when_locked_branch_moved {
+    let bar = simple_bar();
+
+    let manifest = format!(r#"
+        [project]
+        name = "foo"
+        version = "0.5.0"
+        authors = []
+
+        [dependencies.bar]
+        git = '{}'
+    "#, bar.url());
+    let p1 = project("p1")
+        .file("Cargo.toml", manifest.as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p1.cargo_process("build"), execs().with_status(0));
+
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn bar() {}")
+        .assert();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    add(&repo);
+    commit(&repo);
+
+    // Building another project fetches the new commit into the database,
+    // while the first one remains locked to the old one.
+    let p2 = project("p2")
+        .file("Cargo.toml", manifest.as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p2.cargo_process("build"), execs().with_status(0));
+
+    assert_that(p1.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(0)
+                       .with_stdout("")
+                       .with_stderr(format!("\
+branch `master` of {} has moved from [..] to [..]; run `cargo update` to pick \
+it up
+", bar.url()).as_slice()));
+});
+
+test!(broken_database_is_cloned_again {
+    let bar = simple_bar();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    // Lose all of the objects of the database, as if writing them was
+    // interrupted, but leave its references behind.
+    let db = paths::home().join(".cargo/git/db");
+    for db in fs::readdir(&db).unwrap().iter() {
+        let objects = db.join("objects");
+        fs::rmdir_recursive(&objects).unwrap();
+        fs::mkdir(&objects, USER_DIR).unwrap();
+    }
+
+    assert_that(p.process(cargo_dir().join("cargo")).arg("update"),
+                execs().with_status(0)
+                       .with_stdout(format!("{} git repository `{}`",
+                                            UPDATING, bar.url())));
+});
+
+fn tag(repo: &git2::Repository, name: &str) {
+    let head = repo.head().unwrap().target().unwrap();
+    repo.tag(name,
+             &repo.find_object(head, None).unwrap(),
+             &repo.signature().unwrap(),
+             "make a new tag",
+             false).unwrap();
+}
+
+// A git repository holding an empty `bar` library
+fn simple_bar() -> ProjectBuilder {
+    git_repo("bar", |project| {
+        project.file("Cargo.toml", r#"
+            [package]
+            name = "bar"
+            version = "0.5.0"
+            authors = []
+        "#)
+        .file("src/lib.rs", "")
+    }).assert()
+}
+
+test!(tags_matched_ignoring_case {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    tag(&repo, "V1.0");
+    tag(&repo, "V2.0-RC");
+    tag(&repo, "v2.0-Rc");
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+            tag = "v1.0"
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", r#"
+            [git]
+            case-insensitive-refs = true
+        "#);
+    assert_that(p.cargo_process("build"),
+                execs().with_status(0)
+                       .with_stderr(format!("\
+`refs/tags/v1.0` doesn't exist in {}, using `refs/tags/V1.0` which only \
+differs in case
+", bar.url()).as_slice()));
+
+    File::create(&p.root().join("Cargo.toml")).write_str(format!(r#"
+        [project]
+        name = "foo"
+        version = "0.5.0"
+        authors = []
+
+        [dependencies.bar]
+        git = '{}'
+        tag = "v2.0-rc"
+    "#, bar.url()).as_slice()).unwrap();
+    assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(101)
+                       .with_stderr(format!("\
+Unable to update {url}[..]
+
+Caused by:
+  `refs/tags/v2.0-rc` doesn't exist in {url}, and more than one reference \
+matches it when ignoring case: refs/tags/V2.0-RC, refs/tags/v2.0-Rc
+", url = bar.url()).as_slice()));
+});
+
+test!(annotated_and_lightweight_tags {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    tag(&repo, "annotated");
+    let head = repo.head().unwrap().target().unwrap();
+    File::create(&bar.root().join(".git/refs/tags/lightweight"))
+         .write_str(format!("{}\n", head).as_slice()).unwrap();
+
+    let manifest = |name: &str| format!(r#"
+        [project]
+        name = "foo"
+        version = "0.5.0"
+        authors = []
+
+        [dependencies.bar]
+        git = '{}'
+        tag = "{}"
+    "#, bar.url(), name);
+    let p = project("foo")
+        .file("Cargo.toml", manifest("lightweight").as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"),
+                execs().with_status(0)
+                       .with_stdout(format!("\
+{cloning} git repository `{url}`
+{compiling} bar v0.5.0 ({url}?tag=lightweight#[..])
+{compiling} foo v0.5.0 ({dir})
+", cloning = CLONING, compiling = COMPILING, url = bar.url(),
+   dir = p.url()).as_slice()));
+
+    File::create(&p.root().join("Cargo.toml"))
+         .write_str(manifest("annotated").as_slice()).unwrap();
+    assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(0)
+                       .with_stdout(format!("\
+{updating} git repository `{url}`
+{compiling} bar v0.5.0 ({url}?tag=annotated#[..])
+{compiling} foo v0.5.0 ({dir})
+", updating = UPDATING, compiling = COMPILING, url = bar.url(),
+   dir = p.url()).as_slice()));
+});
+
+test!(revs_naming_annotated_tags_are_peeled {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    tag(&repo, "v1.0");
+
+    let manifest = |rev: &str| format!(r#"
+        [project]
+        name = "foo"
+        version = "0.5.0"
+        authors = []
+
+        [dependencies.bar]
+        git = '{}'
+        rev = "{}"
+    "#, bar.url(), rev);
+    let p = project("foo")
+        .file("Cargo.toml", manifest("v1.0").as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    // A tree can't be peeled to a commit at all
+    File::create(&p.root().join("Cargo.toml"))
+         .write_str(manifest("v1.0^{tree}").as_slice()).unwrap();
+    p.root().join("Cargo.lock").rm_rf().unwrap();
+    assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(101)
+                       .with_stderr(format!("\
+Unable to update {url}[..]
+
+Caused by:
+  revision `v1.0^{{tree}}` doesn't refer to a commit
+", url = bar.url()).as_slice()));
+});
+
+test!(missing_branch_fails_before_cloning {
+    let bar = simple_bar();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+            branch = "gone"
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"),
+                execs().with_status(101)
+                       .with_stderr(format!("\
+Unable to update {url}[..]
+
+Caused by:
+  branch `gone` doesn't exist in `{url}`
+", url = bar.url()).as_slice()));
+});
+
+test!(databases_borrow_objects_from_alternates {
+    let bar = simple_bar();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", format!(r#"
+            [git]
+            alternates = ['{}']
+        "#, bar.root().display()).as_slice());
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    // The objects were copied in the end, so the database doesn't depend on
+    // the alternate anymore
+    let head = git2::Repository::open(&bar.root()).unwrap()
+                   .head().unwrap().target().unwrap();
+    fs::rmdir_recursive(&bar.root()).unwrap();
+    let db = paths::home().join(".cargo/git/db");
+    for db in fs::readdir(&db).unwrap().iter() {
+        assert!(!db.join("objects/info/alternates").exists());
+        assert!(!db.join("refs/alternates").exists());
+        let repo = git2::Repository::open(db).unwrap();
+        assert!(repo.find_commit(head).is_ok());
+    }
+});
+
+test!(databases_borrowing_objects_are_dissociated_on_update {
+    let bar = simple_bar();
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let master = GitReference::Branch("master".to_string());
+    let path = paths::root().join("db");
+    remote.checkout(&path, &master).unwrap();
+
+    // As left behind by older versions of cargo, with an alternate which has
+    // since been deleted
+    let gone = paths::root().join("gone/objects");
+    File::create(&path.join("objects/info/alternates"))
+         .write_str(format!("{}\n", gone.display()).as_slice()).unwrap();
+
+    let db = remote.checkout(&path, &master).unwrap();
+    assert!(!path.join("objects/info/alternates").exists());
+    assert!(db.rev_for(&master).is_ok());
+});
+
+test!(warn_when_tag_moved_upstream {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    tag(&repo, "v1.0");
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+            tag = "v1.0"
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn bar() {}")
+         .unwrap();
+    add(&repo);
+    let moved = commit(&repo);
+    repo.tag("v1.0",
+             &repo.find_object(moved, None).unwrap(),
+             &repo.signature().unwrap(),
+             "move the tag",
+             true).unwrap();
+
+    assert_that(p.process(cargo_dir().join("cargo")).arg("update"),
+                execs().with_status(0)
+                       .with_stdout(format!("{} git repository `{}`",
+                                            UPDATING, bar.url()))
+                       .with_stderr(format!("\
+tag `v1.0` of {} was moved upstream from [..] to [..], using its new target
+", bar.url()).as_slice()));
+});
+
+test!(sparse_checkout_of_a_subdirectory {
+    let bar = git_repo("bar", |project| {
+        project.file("Cargo.toml", r#"
+            [package]
+            name = "unused"
+            version = "0.5.0"
+            authors = []
+        "#)
+        .file("src/lib.rs", "")
+        .file("crates/bar/Cargo.toml", r#"
+            [package]
+            name = "bar"
+            version = "0.5.0"
+            authors = []
+        "#)
+        .file("crates/bar/src/lib.rs", "")
+        .file("assets/huge.bin", "not needed")
+    }).assert();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", format!(r#"
+            [git.sparse-paths]
+            "{}" = ["crates/bar"]
+        "#, bar.url()).as_slice());
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    let checkouts = paths::home().join(".cargo/git/checkouts/_local");
+    for repo in fs::readdir(&checkouts).unwrap().iter() {
+        for checkout in fs::readdir(repo).unwrap().iter() {
+            assert_that(&checkout.join("Cargo.toml"), existing_file());
+            assert_that(&checkout.join("crates/bar/src/lib.rs"), existing_file());
+            assert!(!checkout.join("assets").exists());
+        }
+    }
+});
+
+test!(sparse_checkouts_are_made_full_again {
+    let bar = git_repo("bar", |project| {
+        project.file("Cargo.toml", r#"
+            [package]
+            name = "unused"
+            version = "0.5.0"
+            authors = []
+        "#)
+        .file("src/lib.rs", "")
+        .file("crates/bar/Cargo.toml", r#"
+            [package]
+            name = "bar"
+            version = "0.5.0"
+            authors = []
+        "#)
+        .file("crates/bar/src/lib.rs", "")
+        .file("assets/huge.bin", "not needed")
+    }).assert();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", format!(r#"
+            [git.sparse-paths]
+            "{}" = ["crates/bar"]
+        "#, bar.url()).as_slice());
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    let checkouts = paths::home().join(".cargo/git/checkouts");
+    let checkout = fs::walk_dir(&checkouts).unwrap().find(|path| {
+        path.filename_str() == Some("cargo-last-used")
+    }).unwrap().dir_path().dir_path();
+    // Pointing HEAD nowhere makes the next build reset the checkout.
+    let break_head = || {
+        File::create(&checkout.join(".git/HEAD")).write_str("ref: refs/heads/gone\n")
+                                                .unwrap();
+    };
+
+    // Changes are only reported for the files the sparse checkout has.
+    File::create(&checkout.join("crates/bar/src/lib.rs")).write_str("// changed")
+                                                        .unwrap();
+    break_head();
+    assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(0).with_stderr(format!("\
+discarding changes to the checkout of `{}` at [..]:
+    crates/bar/src/lib.rs
+", bar.url()).as_slice()));
+
+    File::create(&p.root().join(".cargo/config")).write_str("").unwrap();
+    break_head();
+    assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(0));
+    assert_that(&checkout.join("assets/huge.bin"), existing_file());
+    assert!(!checkout.join(".git/info/sparse-checkout").exists());
+});
+
+test!(skip_configured_submodules {
+    let bar = simple_bar();
+    let docs = git_repo("docs", |project| {
+        project.file("index.md", "")
+    }).assert();
+
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let url = path2url(docs.root()).to_string();
+    add_submodule(&repo, url.as_slice(), &Path::new("docs"));
+    commit(&repo);
+    // The submodule can't be fetched any more, so it must not be tried.
+    fs::rmdir_recursive(&docs.root()).unwrap();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", r#"
+            [git]
+            skip-submodules = ["doc*"]
+        "#);
+    assert_that(p.cargo_process("build"),
+                execs().with_status(0)
+                       .with_stderr(format!("\
+not checking out these submodules of `{}`, as configured:
+    docs
+", bar.url()).as_slice()));
+});
+
+test!(branches_found_as_local_or_remote_tracking {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let head = repo.find_commit(repo.head().unwrap().target().unwrap()).unwrap();
+    repo.branch("feature", &head, false, None, None).unwrap();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+            branch = "feature"
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+
+    // The branch is fetched into the database as a local branch
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    // Turn it into a remote-tracking branch instead, which must still resolve
+    // to the same revision. A build would fetch the local branch again, so
+    // look it up in the database directly.
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let reference = GitReference::Branch("feature".to_string());
+    let dbs = paths::home().join(".cargo/git/db");
+    for path in fs::readdir(&dbs).unwrap().iter().filter(|p| p.is_dir()) {
+        let remotes = path.join("refs/remotes/origin");
+        fs::mkdir_recursive(&remotes, USER_DIR).unwrap();
+        fs::rename(&path.join("refs/heads/feature"),
+                   &remotes.join("feature")).unwrap();
+        assert!(!path.join("refs/heads/feature").exists());
+
+        let db = remote.db_at(path).unwrap();
+        let rev = db.rev_for(&reference).unwrap();
+        assert_eq!(rev.to_string(), head.id().to_string());
+    }
+});
+
+test!(default_branch_from_config {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let head = repo.find_commit(repo.head().unwrap().target().unwrap()).unwrap();
+    repo.branch("main", &head, false, None, None).unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+    repo.find_branch("master", git2::BranchType::Local).unwrap()
+        .delete().unwrap();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", r#"
+            [git]
+            default-branch = "main"
+        "#);
+    assert_that(p.cargo_process("generate-lockfile").arg("-v"),
+                execs().with_status(0)
+                       .with_stdout(format!("\
+{cloning} git repository `{url}`
+{using} default branch `main` of `{url}`
+", cloning = CLONING, using = "       Using", url = bar.url()).as_slice()));
+    assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(0));
+});
+
+test!(default_branch_from_remote_head {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let head = repo.find_commit(repo.head().unwrap().target().unwrap()).unwrap();
+    repo.branch("main", &head, false, None, None).unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+    repo.find_branch("master", git2::BranchType::Local).unwrap()
+        .delete().unwrap();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("generate-lockfile").arg("-v"),
+                execs().with_status(0)
+                       .with_stdout(format!("\
+{cloning} git repository `{url}`
+{using} default branch `main` of `{url}`
+", cloning = CLONING, using = "       Using", url = bar.url()).as_slice()));
+    assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(0));
+});
+
+test!(explicit_branches_are_not_reported_as_the_default {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let head = repo.find_commit(repo.head().unwrap().target().unwrap()).unwrap();
+    repo.branch("main", &head, false, None, None).unwrap();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+            branch = "main"
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", r#"
+            [git]
+            default-branch = "main"
+        "#);
+    assert_that(p.cargo_process("generate-lockfile").arg("-v"),
+                execs().with_status(0)
+                       .with_stdout(format!("\
+{cloning} git repository `{url}`
+", cloning = CLONING, url = bar.url()).as_slice()));
+});
+
+test!(checkouts_record_when_they_were_last_used {
+    let bar = simple_bar();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    let markers = || {
+        let checkouts = paths::home().join(".cargo/git/checkouts");
+        fs::walk_dir(&checkouts).unwrap().filter(|path| {
+            path.filename_str() == Some("cargo-last-used")
+        }).collect::<Vec<Path>>()
+    };
+    let first = markers();
+    assert_eq!(first.len(), 1);
+    let used = first[0].stat().unwrap().modified;
+
+    // Reusing the checkout as is still counts as using it
+    timer::sleep(Duration::milliseconds(1000));
+    File::create(&p.root().join("src/main.rs")).write_str("fn main() { }").unwrap();
+    assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(0));
+    assert!(markers()[0].stat().unwrap().modified > used);
+});
+
+test!(dependency_on_an_empty_repository {
+    let empty = paths::root().join("empty");
+    git2::Repository::init(&empty).unwrap();
+    let url = path2url(empty);
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, url).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"),
+                execs().with_status(101)
+                       .with_stderr(format!("\
+Unable to update {url}[..]
+
+Caused by:
+  repository `{url}` has no commits
+", url = url).as_slice()));
+});
+
+test!(only_the_needed_tag_is_fetched {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    tag(&repo, "v1.0");
+    tag(&repo, "v2.0");
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+            tag = "v1.0"
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    let db = paths::home().join(".cargo/git/db");
+    for db in fs::readdir(&db).unwrap().iter() {
+        let db = git2::Repository::open(db).unwrap();
+        assert!(db.refname_to_id("refs/tags/v1.0").is_ok());
+        assert!(db.refname_to_id("refs/tags/v2.0").is_err());
+    }
+});
+
+test!(locked_revision_gone_from_the_remote {
+    let bar = simple_bar();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("generate-lockfile"), execs().with_status(0));
+
+    // Pretend the locked commit was force-pushed away
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let head = repo.head().unwrap().target().unwrap().to_string();
+    let gone = "0123456789abcdef0123456789abcdef01234567";
+    let lockfile = p.root().join("Cargo.lock");
+    let contents = File::open(&lockfile).read_to_string().unwrap();
+    File::create(&lockfile).write_str(contents.replace(head.as_slice(), gone)
+                                              .as_slice()).unwrap();
+    paths::home().join(".cargo/git").rm_rf().unwrap();
+
+    assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(101)
+                       .with_stderr(format!("\
+Unable to update {url}[..]
+
+Caused by:
+  revision `{rev}` not found in `{url}` even after fetching; it may have been \
+force-pushed away or garbage collected, try `cargo update`
+", url = bar.url(), rev = gone).as_slice()));
+});
+
+test!(clones_are_staged_in_the_configured_directory {
+    let bar = simple_bar();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", r#"
+            [git]
+            staging-dir = "staging"
+        "#);
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    // Everything was moved out of the staging directory once it was done
+    let staging = p.root().join("staging");
+    assert!(staging.is_dir());
+    assert_eq!(fs::readdir(&staging).unwrap().len(), 0);
+});
+
+test!(revs_only_reachable_by_fetching_them_directly {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let first = repo.revparse_single("HEAD").unwrap();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn bar() {}")
+                                                .unwrap();
+    add(&repo);
+    commit(&repo);
+    let second = repo.revparse_single("HEAD").unwrap().id();
+
+    // Force-push the second commit away, the server still has it though
+    repo.reset(&first, git2::ResetType::Hard, None, None).unwrap();
+    repo.config().unwrap().set_bool("uploadpack.allowAnySHA1InWant", true)
+        .unwrap();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+            rev = "{}"
+        "#, bar.url(), second).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+});
+
+test!(git_protocol_can_be_denied {
+    let p = project("foo")
+        .file("Cargo.toml", r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = 'git://example.com/bar'
+        "#)
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", r#"
+            [git]
+            deny-git-protocol = true
+        "#);
+    assert_that(p.cargo_process("build"),
+                execs().with_status(101)
+                       .with_stderr("\
+Unable to update git://example.com/bar
+
+Caused by:
+  refusing to fetch `git://example.com/bar` over the insecure `git://` \
+protocol, as configured with `git.deny-git-protocol`; use an https:// or \
+ssh:// url instead
+"));
+});
+
+test!(damaged_database_config_is_recreated {
+    let bar = simple_bar();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    let db = paths::home().join(".cargo/git/db");
+    for db in fs::readdir(&db).unwrap().iter() {
+        File::create(&db.join("config")).write_str("[cor").unwrap();
+    }
+    assert_that(p.process(cargo_dir().join("cargo")).arg("update"),
+                execs().with_status(0)
+                       .with_stderr("\
+the git configuration at [..]config is missing or damaged, recreating it
+"));
+});
+
+test!(shallow_depth_depends_on_the_kind_of_reference {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let first = repo.head().unwrap().target().unwrap();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn bar() {}")
+                                                .unwrap();
+    add(&repo);
+    commit(&repo);
+    tag(&repo, "v1.0");
+
+    let config = r#"
+        [git]
+        tag-depth = 1
+    "#;
+    let foo = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+            tag = "v1.0"
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", config);
+    assert_that(foo.cargo_process("build"), execs().with_status(0));
+
+    let db = paths::home().join(".cargo/git/db");
+    let dbs = fs::readdir(&db).unwrap();
+    assert_eq!(dbs.len(), 1);
+    assert!(dbs[0].join("shallow").exists());
+
+    // A rev may be anywhere in the history, so all of it has to be fetched
+    let baz = project("baz")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "baz"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+            rev = "{}"
+        "#, bar.url(), first).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", config);
+    assert_that(baz.cargo_process("build"), execs().with_status(0));
+    assert!(!dbs[0].join("shallow").exists());
+});
+
+test!(checkouts_can_be_exported_without_git_metadata {
+    let bar = git_repo("bar", |project| {
+        project.file("Cargo.toml", r#"
+            [package]
+            name = "bar"
+            version = "0.5.0"
+            authors = []
+        "#)
+        .file("src/lib.rs", "pub fn bar() {}")
+        .file("run.sh", "#!/bin/sh")
+    }).assert();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    fs::chmod(&bar.root().join("run.sh"), USER_RWX).unwrap();
+    add(&repo);
+    commit(&repo);
+
+    let url = path2url(bar.root());
+    let remote = GitRemote::new(&url);
+    let reference = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &reference).unwrap();
+    let rev = db.rev_for(&reference).unwrap();
+    let (checkout, _) = db.copy_to(rev, &paths::root().join("checkout")).unwrap();
+
+    let dst = paths::root().join("vendor/bar");
+    checkout.export(&dst).unwrap();
+    assert!(!dst.join(".git").exists());
+    assert_eq!(File::open(&dst.join("src/lib.rs")).read_to_string().unwrap(),
+               "pub fn bar() {}");
+    if cfg!(unix) {
+        let perm = dst.join("run.sh").stat().unwrap().perm;
+        assert!(perm.contains(USER_EXECUTE));
+    }
+});
+
+test!(files_changed_between_revisions {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let first = repo.head().unwrap().target().unwrap().to_string();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn bar() {}")
+                                                .unwrap();
+    File::create(&bar.root().join("src/foo.rs")).write_str("").unwrap();
+    add(&repo);
+    let second = commit(&repo).to_string();
+
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let reference = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &reference).unwrap();
+    let from = db.rev_for(&GitReference::Rev(first)).unwrap();
+    let to = db.rev_for(&GitReference::Rev(second)).unwrap();
+    assert_eq!(db.diff(from, to).unwrap(),
+               vec![(ChangeKind::Added, Path::new("src/foo.rs")),
+                    (ChangeKind::Modified, Path::new("src/lib.rs"))]);
+});
+
+test!(untracked_files_are_cleaned_out_of_checkouts {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let first = repo.head().unwrap().target().unwrap().to_string();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn bar() {}")
+                                                .unwrap();
+    add(&repo);
+    commit(&repo);
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}")
+        .file(".cargo/config", r#"
+            [git]
+            clean-untracked = true
+        "#);
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+
+    // Leave a stray file behind and move the checkout off its revision, so
+    // it's reset by the next build
+    let checkouts = paths::home().join(".cargo/git/checkouts");
+    let checkout = fs::walk_dir(&checkouts).unwrap().find(|path| {
+        path.filename_str() == Some("cargo-last-used")
+    }).unwrap().dir_path().dir_path();
+    File::create(&checkout.join("src/stale.rs")).write_str("").unwrap();
+    File::create(&checkout.join(".git/HEAD")).write_str(first.as_slice()).unwrap();
+
+    assert_that(p.process(cargo_dir().join("cargo")).arg("build"),
+                execs().with_status(0));
+    assert!(!checkout.join("src/stale.rs").exists());
+    assert_that(&checkout.join("src/lib.rs"), existing_file());
+});
+
+test!(dependency_on_a_pull_request_ref {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let first = repo.revparse_single("HEAD").unwrap();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn bar() {}")
+                                                .unwrap();
+    add(&repo);
+    let change = commit(&repo);
+
+    // The change is only reachable from the ref of the pull request
+    repo.reference("refs/pull/1/head", change, false, "pull request").unwrap();
+    repo.reset(&first, git2::ResetType::Hard, None, None).unwrap();
+
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+            rev = "refs/pull/1/head"
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "extern crate bar; fn main() { bar::bar() }");
+    assert_that(p.cargo_process("build"), execs().with_status(0));
+});
+
+test!(checkouts_pinned_to_the_wrong_tree_are_refused {
+    let bar = simple_bar();
+
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let reference = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &reference).unwrap();
+    let rev = db.rev_for(&reference).unwrap();
+    let tree = db.tree_id(&rev).unwrap();
+
+    let dest = paths::root().join("checkout");
+    assert!(db.copy_to_pinned(rev.clone(), &dest, tree.as_slice()).is_ok());
+    let wrong = "0123456789abcdef0123456789abcdef01234567";
+    assert!(db.copy_to_pinned(rev.clone(), &dest, wrong).is_err());
+
+    // The checkout is still at the right revision, but its files were edited
+    File::create(&dest.join("src/lib.rs")).write_str("pub fn f() {}").unwrap();
+    assert!(db.copy_to_pinned(rev.clone(), &dest, tree.as_slice()).is_err());
+    File::create(&dest.join("src/lib.rs")).write_str("").unwrap();
+    assert!(db.copy_to_pinned(rev, &dest, tree.as_slice()).is_ok());
+});
+
+struct Closer { a: TcpAcceptor }
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        let _ = self.a.close_accept();
+    }
+}
+
+// Makes `bar` depend on a `docs` submodule which is fetched from `url`
+fn bar_with_remote_submodule(url: &str) -> ProjectBuilder {
+    let bar = simple_bar();
+    let docs = git_repo("docs", |project| {
+        project.file("index.md", "")
+    }).assert();
+
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let local = path2url(docs.root()).to_string();
+    add_submodule(&repo, local.as_slice(), &Path::new("docs"));
+    let mut modules = git2::Config::open(&bar.root().join(".gitmodules")).unwrap();
+    modules.set_str("submodule.docs.url", url).unwrap();
+    drop(modules);
+    add(&repo);
+    commit(&repo);
+    bar
+}
+
+test!(submodules_are_fetched_with_credentials_over_http {
+    let mut listener = TcpListener::bind("127.0.0.1:0").assert();
+    let addr = listener.socket_name().assert();
+    let mut a = listener.listen().unwrap();
+    let a2 = a.clone();
+    let _c = Closer { a: a2 };
+
+    // Turns every request away, returning whether any of them offered
+    // credentials.
+    let t = Thread::spawn(move|| {
+        let mut offered = false;
+        for conn in a.incoming() {
+            let mut s = match conn {
+                Ok(s) => BufferedStream::new(s),
+                Err(..) => break,
+            };
+            let authorized = s.lines().map(|l| l.unwrap_or(String::new()))
+                              .take_while(|l| l.len() > 2)
+                              .any(|l| l.as_slice().starts_with("Authorization"));
+            offered = offered || authorized;
+            let _ = s.write(b"\
+                HTTP/1.1 401 Unauthorized\r\n\
+                WWW-Authenticate: Basic realm=\"wheee\"\r\n\
+                \r\n\
+            ");
+        }
+        offered
+    });
+
+    let script = project("script")
+        .file("Cargo.toml", r#"
+            [project]
+            name = "script"
+            version = "0.0.1"
+            authors = []
+        "#)
+        .file("src/main.rs", r#"
+            fn main() {
+                println!("username=foo");
+                println!("password=bar");
+            }
+        "#);
+    assert_that(script.cargo_process("build"), execs().with_status(0));
+    let script = script.bin("script");
+
+    let config = paths::home().join(".gitconfig");
+    let mut config = git2::Config::open(&config).unwrap();
+    config.set_str("credential.helper",
+                   script.display().to_string().as_slice()).unwrap();
+
+    let url = format!("http://{}/docs", addr);
+    let bar = bar_with_remote_submodule(url.as_slice());
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"), execs().with_status(101));
+
+    drop(_c);
+    assert!(t.join().ok().unwrap());
+});
+
+test!(submodules_are_fetched_over_ssh {
+    let mut listener = TcpListener::bind("127.0.0.1:0").assert();
+    let addr = listener.socket_name().assert();
+    let mut a = listener.listen().unwrap();
+    let a2 = a.clone();
+    let _c = Closer { a: a2 };
+    let t = Thread::spawn(move|| {
+        drop(a.accept().unwrap());
+    });
+
+    let url = format!("ssh://{}/docs", addr);
+    let bar = bar_with_remote_submodule(url.as_slice());
+    let p = project("foo")
+        .file("Cargo.toml", format!(r#"
+            [project]
+            name = "foo"
+            version = "0.5.0"
+            authors = []
+
+            [dependencies.bar]
+            git = '{}'
+        "#, bar.url()).as_slice())
+        .file("src/main.rs", "fn main() {}");
+    assert_that(p.cargo_process("build"), execs().with_status(101));
+
+    // The submodule's fetch went out to the ssh server instead of failing
+    // before it for lack of a way to authenticate.
+    t.join().ok().unwrap();
+});
+
+test!(existing_checkouts_are_opened_at_their_revision_only {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn f() {}").unwrap();
+    add(&repo);
+    commit(&repo);
+
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let reference = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &reference).unwrap();
+    let second = db.rev_for(&reference).unwrap();
+    let first = db.rev_for(&GitReference::Rev(format!("{}^", second))).unwrap();
+
+    let dest = paths::root().join("checkout");
+    assert!(GitCheckout::open_existing(&dest, &db, second.clone()).is_err());
+    db.copy_to(second.clone(), &dest).unwrap();
+    assert!(GitCheckout::open_existing(&dest, &db, second).is_ok());
+    assert!(GitCheckout::open_existing(&dest, &db, first).is_err());
+});
+
+test!(single_references_are_fetched_into_databases {
+    let bar = simple_bar();
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let master = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &master).unwrap();
+
+    // A branch which only shows up upstream after the database was created
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn f() {}").unwrap();
+    add(&repo);
+    let tip = commit(&repo);
+    let head = repo.find_commit(tip).unwrap();
+    repo.branch("feature", &head, false, None, None).unwrap();
+
+    let feature = GitReference::Branch("feature".to_string());
+    assert!(db.rev_for(&feature).is_err());
+    let rev = db.fetch_ref(&feature).unwrap();
+    assert_eq!(rev.to_string(), tip.to_string());
+    assert_eq!(db.rev_for(&feature).unwrap(), rev);
+});
+
+test!(references_are_resolved_together {
+    let bar = simple_bar();
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let master = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &master).unwrap();
+    let old = db.rev_for(&master).unwrap();
+
+    // master moves on upstream, and a branch shows up next to it
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn f() {}").unwrap();
+    add(&repo);
+    let tip = commit(&repo);
+    let head = repo.find_commit(tip).unwrap();
+    repo.branch("feature", &head, false, None, None).unwrap();
+
+    let feature = GitReference::Branch("feature".to_string());
+    let revs = db.rev_for_many(&[master.clone(), feature.clone()]).unwrap();
+    assert_eq!(revs.len(), 2);
+    assert_eq!(revs[0].to_string(), tip.to_string());
+    assert_eq!(revs[1].to_string(), tip.to_string());
+    assert!(revs[0] != old);
+    assert_eq!(db.rev_for(&feature).unwrap(), revs[1]);
+
+    let missing = GitReference::Branch("missing".to_string());
+    assert!(db.rev_for_many(&[master, missing]).is_err());
+});
+
+test!(databases_are_seeded_from_bundles {
+    let bar = simple_bar();
+    let bundle = paths::root().join("bar.bundle");
+    let bundle_arg = bundle.display().to_string();
+    bar.process("git").args(&["bundle", "create", bundle_arg.as_slice(), "--all"])
+       .exec_with_output().assert();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let head = repo.head().unwrap().target().unwrap();
+
+    // Nothing is fetched from the remote itself, which doesn't even exist
+    let remote = GitRemote::new(&path2url(paths::root().join("nowhere")));
+    let master = GitReference::Branch("master".to_string());
+    let db = remote.checkout_from_bundle(&paths::root().join("db"), &bundle,
+                                         &master).unwrap();
+    assert_eq!(db.rev_for(&master).unwrap().to_string(), head.to_string());
+
+    let missing = GitReference::Branch("missing".to_string());
+    assert!(remote.checkout_from_bundle(&paths::root().join("db2"), &bundle,
+                                        &missing).is_err());
+});
+
+test!(failed_clones_leave_what_was_there_before {
+    let db = paths::root().join("db");
+    fs::mkdir_recursive(&db, USER_DIR).unwrap();
+    File::create(&db.join("keep")).write_str("").unwrap();
+
+    // What's at `db` isn't a repository so it's cloned again, from a remote
+    // which doesn't exist
+    let remote = GitRemote::new(&path2url(paths::root().join("nowhere")));
+    let master = GitReference::Branch("master".to_string());
+    assert!(remote.checkout(&db, &master).is_err());
+    assert_that(&db.join("keep"), existing_file());
+});
+
+test!(files_are_read_straight_from_databases {
+    let bar = simple_bar();
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let master = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &master).unwrap();
+    let rev = db.rev_for(&master).unwrap();
+
+    assert_eq!(db.read_file(&rev, &Path::new("src/lib.rs")).unwrap(), Vec::new());
+    let manifest = db.read_file(&rev, &Path::new("Cargo.toml")).unwrap();
+    assert!(String::from_utf8(manifest).unwrap().as_slice().contains("name = \"bar\""));
+
+    // Directories and paths which don't exist can't be read
+    assert!(db.read_file(&rev, &Path::new("src")).is_err());
+    assert!(db.read_file(&rev, &Path::new("src/main.rs")).is_err());
+    assert!(db.read_file(&rev, &Path::new("src/lib.rs/foo")).is_err());
+});
+
+test!(forced_copies_fetch_the_database_first {
+    let bar = simple_bar();
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let master = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &master).unwrap();
+    let dest = paths::root().join("checkout");
+    db.copy_to(db.rev_for(&master).unwrap(), &dest).unwrap();
+
+    // A commit which only the remote has so far
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn f() {}").unwrap();
+    add(&repo);
+    let tip = commit(&repo);
+    let rev = from_str::<GitRevision>(tip.to_string().as_slice()).unwrap();
+
+    assert!(db.copy_to(rev.clone(), &dest).is_err());
+    db.copy_to_forced(rev, &dest).unwrap();
+    assert_eq!(File::open(&dest.join("src/lib.rs")).read_to_string().unwrap().as_slice(),
+               "pub fn f() {}");
+});
+
+#[cfg(unix)]
+test!(exports_replace_what_was_exported_before {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    fs::symlink(&Path::new("src/lib.rs"), &bar.root().join("lib.rs")).unwrap();
+    add(&repo);
+    commit(&repo);
+
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let reference = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &reference).unwrap();
+    let rev = db.rev_for(&reference).unwrap();
+    let (checkout, _) = db.copy_to(rev, &paths::root().join("checkout")).unwrap();
+
+    let dst = paths::root().join("vendor/bar");
+    checkout.export(&dst).unwrap();
+
+    // A symlink left where a file goes isn't written through
+    let outside = paths::root().join("outside");
+    File::create(&outside).write_str("outside").unwrap();
+    fs::unlink(&dst.join("Cargo.toml")).unwrap();
+    fs::symlink(&outside, &dst.join("Cargo.toml")).unwrap();
+
+    checkout.export(&dst).unwrap();
+    assert_eq!(fs::readlink(&dst.join("lib.rs")).unwrap(), Path::new("src/lib.rs"));
+    assert!(fs::readlink(&dst.join("Cargo.toml")).is_err());
+    assert_eq!(File::open(&outside).read_to_string().unwrap().as_slice(), "outside");
+});
+
+test!(checkouts_are_read_from_other_threads_through_handles {
+    let bar = simple_bar();
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let master = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &master).unwrap();
+    let rev = db.rev_for(&master).unwrap();
+    let dest = paths::root().join("checkout");
+    let (checkout, _) = db.copy_to(rev.clone(), &dest).unwrap();
+    let handle = checkout.handle();
+    assert_eq!(handle.location(), &dest);
+    assert_eq!(handle.revision(), &rev);
+
+    let t = Thread::spawn(move|| {
+        let repo = handle.open().unwrap();
+        repo.workdir().unwrap().join("Cargo.toml").is_file()
+    });
+    assert!(t.join().ok().unwrap());
+
+    // Once the checkout is moved to another revision the handle is stale
+    let handle = checkout.handle();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn f() {}").unwrap();
+    add(&repo);
+    commit(&repo);
+    let tip = db.fetch_ref(&master).unwrap();
+    db.copy_to(tip, &dest).unwrap();
+    assert!(handle.open().is_err());
+});
+
+struct FixedCredentials;
+
+impl CredentialProvider for FixedCredentials {
+    fn credentials(&mut self, _url: &str, _username: Option<&str>,
+                   _allowed: git2::CredentialType) -> Option<git2::Cred> {
+        git2::Cred::userpass_plaintext("user", "pass").ok()
+    }
+}
+
+test!(credential_providers_not_dropped_for_fetches_with_git {
+    let root = paths::root().join("db");
+    let repo = git2::Repository::init_bare(&root).unwrap();
+    // libgit2 can't honor `http.postBuffer`, so this would be fetched with git
+    repo.config().unwrap().set_i64("http.postBuffer", 1048576).unwrap();
+
+    let err = fetch_with_credentials(&repo, "http://127.0.0.1:1/bar",
+                                     "refs/heads/*:refs/heads/*",
+                                     &mut FixedCredentials).unwrap_err();
+    assert_eq!(err.kind(), GitErrorKind::Auth);
+    assert_eq!(err.description(),
+               "credentials from a credential provider can't be used to fetch \
+                `http://127.0.0.1:1/bar`, it has to be fetched with the `git` \
+                executable");
+});
+
+test!(only_missing_revs_deepen_shallow_databases {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let first = repo.head().unwrap().target().unwrap();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn bar() {}")
+                                                .unwrap();
+    add(&repo);
+    commit(&repo);
+
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let db = remote.clone_into_shallow(&paths::root().join("db"), 1).unwrap();
+    assert!(db.is_shallow());
+
+    // Looking for a branch which doesn't exist is no reason to fetch anything
+    let missing = GitReference::Branch("missing".to_string());
+    assert!(db.rev_for(&missing).is_err());
+    assert!(db.is_shallow());
+
+    let rev = GitReference::Rev(first.to_string());
+    assert_eq!(db.rev_for(&rev).unwrap().to_string(), first.to_string());
+    assert!(!db.is_shallow());
+});
+
+test!(pruning_removes_checkouts_along_with_their_lock_files {
+    let bar = simple_bar();
+    let repo = git2::Repository::open(&bar.root()).unwrap();
+    let first = repo.head().unwrap().target().unwrap();
+    File::create(&bar.root().join("src/lib.rs")).write_str("pub fn bar() {}")
+                                                .unwrap();
+    add(&repo);
+    commit(&repo);
+
+    let remote = GitRemote::new(&path2url(bar.root()));
+    let master = GitReference::Branch("master".to_string());
+    let db = remote.checkout(&paths::root().join("db"), &master).unwrap();
+    let old = db.rev_for(&GitReference::Rev(first.to_string())).unwrap();
+    let new = db.rev_for(&master).unwrap();
+    let checkouts = paths::root().join("checkouts");
+    let old_path = checkouts.join(old.to_string());
+    let new_path = checkouts.join(new.to_string());
+    db.copy_to(old.clone(), &old_path).unwrap();
+    db.copy_to(new.clone(), &new_path).unwrap();
+
+    db.prune(&checkouts, &[new]).unwrap();
+    assert!(!old_path.exists());
+    assert!(!checkouts.join(format!("{}.lock", old)).exists());
+    assert!(new_path.join("Cargo.toml").is_file());
 });